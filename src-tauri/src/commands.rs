@@ -1,30 +1,51 @@
 //! Tauri commands for mod management
 //!
 //! This module implements all the core functionality:
-//! - Self-healing environment setup
+//! - Self-healing environment setup (including recovery of interrupted installs)
 //! - Workshop mod scanning
-//! - VPK merging and compilation
+//! - VPK merging and compilation (with dry-run plans and per-path winner rules)
+//! - VPK verification and extraction
+//! - Mod cache maintenance
 
-use crate::mod_types::{MergeResult, Mod};
+use crate::filters;
+use crate::install;
+use crate::mod_cache::ModCache;
+use crate::mod_types::{
+    CacheReport, ExtractProgress, ExtractSummary, LiteSavings, MergeConflict, MergeOptions, MergePlan,
+    MergeResult, Mod, ModConflictCount, PlannedFile, RejectedFile, SkippedMod, StrippedFile, VerifyReport,
+    WinnerRule,
+};
 use crate::paths::{
-    get_gameinfo_path, get_mods_path, get_workshop_path, TEMP_NAME,
+    get_gameinfo_path, get_install_journal_path, get_mod_cache_path, get_mods_path, get_scratch_path,
+    get_workshop_path, FINGERPRINT_FILE, TEMP_NAME,
 };
-use crate::vpk_utils;
-use std::fs::{self, File};
-use std::io::Write;
+use crate::settings::{self, Settings};
+use crate::vpk_reader::VpkArchive;
+use crate::vpk_utils::{self, EntrySource, PackEntry};
+use crate::vtf;
+use md5::{Digest, Md5};
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::Path;
-use valve_pak::VPK;
 use tauri::Emitter;
 
 /// Verifies and repairs the game environment on startup.
 ///
 /// This function is idempotent and performs:
-/// 1. Creates the `mods` folder if it doesn't exist
-/// 2. Injects "Game mods" into gameinfo.txt if not present
+/// 1. Rolls back an install interrupted by a crash or power loss
+/// 2. Creates the `mods` folder if it doesn't exist
+/// 3. Injects "Game mods" into gameinfo.txt if not present
 #[tauri::command]
 pub fn verify_and_repair_environment() -> Result<(), String> {
     println!("Verificando entorno del juego...");
 
+    // 0. Undo a half-finished install before anything reads the game folders
+    match install::recover_interrupted_install(&get_install_journal_path()) {
+        Ok(true) => println!("[OK] Instalación interrumpida revertida."),
+        Ok(false) => {}
+        Err(e) => eprintln!("Error revirtiendo instalación interrumpida: {}", e),
+    }
+
     // 1. Ensure mods folder exists
     let mods_path = get_mods_path();
     if !mods_path.exists() {
@@ -42,6 +63,29 @@ pub fn verify_and_repair_environment() -> Result<(), String> {
     Ok(())
 }
 
+/// Removes files left behind by merges that didn't finish: the scratch folder,
+/// packs built inside the Workshop folder by older versions and staged install
+/// files. Staged files are kept while an install journal still needs them.
+pub fn clean_merge_leftovers() {
+    let _ = fs::remove_dir_all(get_scratch_path());
+
+    let workshop_path = get_workshop_path();
+    let _ = vpk_utils::remove_vpk_set(&workshop_path.join(format!("{}.vpk", TEMP_NAME)));
+    let _ = fs::remove_dir_all(workshop_path.join(TEMP_NAME));
+
+    if get_install_journal_path().exists() {
+        return;
+    }
+    if let Ok(entries) = fs::read_dir(get_mods_path()) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if install::is_staging_file(&name) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
 /// Injects the "Game mods" line into gameinfo.txt if not already present.
 fn inject_game_mods_line(gameinfo_path: &Path) -> Result<(), String> {
     let content =
@@ -85,12 +129,11 @@ fn inject_game_mods_line(gameinfo_path: &Path) -> Result<(), String> {
         }
     }
 
-    // Write modified file
-    let mut file =
-        File::create(gameinfo_path).map_err(|e| format!("Error escribiendo gameinfo.txt: {}", e))?;
-    for line in new_lines {
-        writeln!(file, "{}", line).map_err(|e| format!("Error escribiendo línea: {}", e))?;
-    }
+    // Write modified file; replaced atomically so a crash never leaves it half-written
+    let mut content = new_lines.join("\n");
+    content.push('\n');
+    install::install_file(gameinfo_path, content.as_bytes(), &get_install_journal_path())
+        .map_err(|e| format!("Error escribiendo gameinfo.txt: {}", e))?;
 
     Ok(())
 }
@@ -116,7 +159,6 @@ pub fn get_mods(window: tauri::Window) -> Result<(), String> {
                 .filter(|entry| {
                     let path = entry.path();
                     path.extension().and_then(|e| e.to_str()) == Some("vpk")
-                        && path.file_stem().and_then(|s| s.to_str()).map(|s| s != TEMP_NAME).unwrap_or(true)
                 })
                 .collect(),
             Err(_) => return,
@@ -167,31 +209,23 @@ pub fn get_mods(window: tauri::Window) -> Result<(), String> {
     Ok(())
 }
 
-/// Extracts the mod title natively using valve_pak crate
-/// OPTIMIZED: Direct file access O(1) only - no iteration
+/// Largest addoninfo.txt or fingerprint file read; the size comes from the VPK tree,
+/// which a hostile mod controls.
+const SMALL_FILE_MAX_SIZE: u64 = 64 * 1024;
+
+/// Extracts the mod title natively using the built-in VPK reader
+/// OPTIMIZED: Only the tree is parsed; just addoninfo.txt is read
 fn get_mod_title_native(vpk_path: &Path) -> Option<String> {
-    // Load VPK
-    let vpk = VPK::open(vpk_path).ok()?;
-    
-    // Try direct access to known paths - O(1)
-    // If not found in these paths, return None (mod will show ID)
-    const KNOWN_PATHS: &[&str] = &[
-        "addoninfo.txt",
-        "AddonInfo.txt", 
-        "ADDONINFO.TXT",
-    ];
-    
-    for path in KNOWN_PATHS {
-        if let Ok(mut file) = vpk.get_file(path) {
-            if let Ok(content) = file.read_all_string() {
-                if let Some(title) = parse_addon_title(&content) {
-                    return Some(title);
-                }
-            }
-        }
-    }
-    
-    None // If not found, mod will display its ID instead
+    // Mods indexed by a previous merge are answered from the cache
+    let cached = ModCache::new(&get_mod_cache_path()).read_file(vpk_path, "addoninfo.txt", SMALL_FILE_MAX_SIZE);
+
+    // Lookup ignores case, so AddonInfo.txt and ADDONINFO.TXT are found too
+    // If not found, return None (mod will show ID)
+    let data = match cached {
+        Some(data) => data,
+        None => VpkArchive::open(vpk_path).ok()?.read_file("addoninfo.txt", SMALL_FILE_MAX_SIZE).ok()?,
+    };
+    parse_addon_title(&String::from_utf8_lossy(&data)) // If not found, mod will display its ID instead
 }
 
 /// Parses the addonTitle from addoninfo.txt content
@@ -242,14 +276,14 @@ fn parse_addon_title(content: &str) -> Option<String> {
 
 /// Deletes the merged VPK file to restore the original game.
 ///
-/// This removes pak01_dir.vpk from the mods folder.
+/// This removes pak01_dir.vpk and its numbered archives from the mods folder.
 #[tauri::command]
 pub fn delete_mods() -> Result<MergeResult, String> {
     let mods_path = get_mods_path();
     let vpk_path = mods_path.join(format!("{}.vpk", TEMP_NAME));
 
     if vpk_path.exists() {
-        fs::remove_file(&vpk_path)
+        vpk_utils::remove_vpk_set(&vpk_path)
             .map_err(|e| format!("Error eliminando VPK: {}", e))?;
         
         println!("[OK] Mods eliminados: {:?}", vpk_path);
@@ -262,41 +296,176 @@ pub fn delete_mods() -> Result<MergeResult, String> {
 /// Merges multiple VPK mods into a single pak01_dir.vpk file.
 ///
 /// Process:
-/// 1. Create temporary directory
-/// 2. Extract each selected VPK
-/// 3. Merge contents (later mods override earlier ones)
-/// 4. Compile into single VPK
-/// 5. Move to mods folder
-/// 6. Cleanup
+/// 0. Return early if the installed pack's fingerprint matches this request
+/// 1. Index the directory tree of each selected VPK (on a worker pool, through the mod cache)
+/// 2. Refuse entries whose path could escape the output folder (absolute, `..`), then
+///    normalize paths to lowercase with forward slashes and resolve overrides (mods
+///    higher in the saved load order override lower ones unless a saved winner rule
+///    pins another mod for the path, with a warning for paths differing only in case),
+///    leaving out files on the strip list (`options.strip_extensions` /
+///    `options.strip_patterns`); in lite mode, shrink VTF textures by dropping their
+///    largest mip levels
+/// 3. Check size limits and free space on the scratch and game volumes, then copy
+///    the winning entries straight from the source VPKs into a single VPK,
+///    storing byte-identical files only once; reads and CRC32s run in parallel
+/// 4. Verify the generated pack (tree, offsets and every CRC32)
+/// 5. Install into the mods folder (directory VPK plus any numbered archives)
+///    through a journaled transaction that rolls back on failure
+///
+/// With `options.incremental`, step 3 first tries to patch the installed pack in
+/// place, appending only changed files; it falls back to a full rebuild when the
+/// pack can't be patched or has become too fragmented.
+///
+/// `ids` only says which mods to merge: they are applied in the saved load order, which
+/// is first synced with the Workshop folder (new mods go to the bottom) and saved.
+/// `options` is optional; when omitted a VPK v1 pack is produced for L4D2.
 #[tauri::command]
-pub fn merge_mods(ids: Vec<String>) -> Result<MergeResult, String> {
+pub fn merge_mods(ids: Vec<String>, options: Option<MergeOptions>) -> Result<MergeResult, String> {
+    let (ids, rules) = ordered_selection(&ids);
     println!("Procesando IDs: {:?}", ids);
+    let options = options.unwrap_or_default();
+    let pack_options = vpk_utils::PackOptions {
+        version: options.vpk_version,
+        archive_size: if options.archive_size > 0 {
+            options.archive_size
+        } else {
+            vpk_utils::DEFAULT_ARCHIVE_SIZE
+        },
+        preload_threshold: options.preload_threshold,
+        // Incremental updates need the data in numbered archives, never in the directory file
+        split_archives: options.incremental,
+        ..Default::default()
+    };
 
     let workshop_path = get_workshop_path();
-    let temp_path = workshop_path.join(TEMP_NAME);
-    // 1. Extract each VPK and merge contents
-    for mod_id in &ids {
-        let vpk_path = workshop_path.join(format!("{}.vpk", mod_id));
+    let mods_path = get_mods_path();
+    let destination_vpk = mods_path.join(format!("{}.vpk", TEMP_NAME));
 
-        if !vpk_path.exists() {
-            continue;
-        }
+    // 0. Nothing to do if the installed pack was built from this exact request
+    let fingerprint = merge_fingerprint(&ids, &options, &rules, &workshop_path);
+    if installed_fingerprint(&destination_vpk).as_deref() == Some(fingerprint.as_str()) {
+        println!("[OK] El pack instalado ya está actualizado.");
+        let mut result = MergeResult::ok("Los mods ya están actualizados.\nNo fue necesario volver a fusionar.");
+        result.up_to_date = true;
+        return Ok(result);
+    }
+
+    // 1. Index the VPKs in parallel (reusing cached file tables), then merge entries
+    //    by path, lowest priority first
+    let resolution = resolve_merge(&ids, &options, &rules, &workshop_path);
+    if let Some(msg) = resolution.refusals.first() {
+        println!("[AVISO] {}", msg);
+        return Ok(MergeResult::error(format!("Error: {}", msg)));
+    }
+    let Resolution {
+        mut merged,
+        stripped_files,
+        rejected_files,
+        warnings,
+        conflicts,
+        conflict_counts,
+        indexed_size,
+        ..
+    } = resolution;
 
-        // Native extraction
-        if let Err(e) = vpk_utils::extract_vpk(&vpk_path, &temp_path) {
-            eprintln!("Error extrayendo {}: {}", mod_id, e);
-            continue; // Skip failed mods but try to continue
+    // Check limits and free space before writing anything
+    let scratch_path = get_scratch_path();
+    if let Err(msg) = preflight(&merged, &options, indexed_size, &scratch_path, &mods_path) {
+        println!("[AVISO] {}", msg);
+        return Ok(MergeResult::error(format!("Error: {}", msg)));
+    }
+    if options.cache_blobs {
+        store_mod_blobs(&ids, &workshop_path);
+    }
+
+    // Lite mode: shrunk textures are staged in the scratch folder until the pack is built
+    let lite_dir = scratch_path.join("lite");
+    let lite_savings = if options.lite_textures {
+        apply_lite_textures(&mut merged, &ids, &options, &lite_dir)?
+    } else {
+        Vec::new()
+    };
+
+    if !stripped_files.is_empty() {
+        println!("[OK] {} archivos innecesarios omitidos", stripped_files.len());
+    }
+
+    let mut entries: Vec<PackEntry> = merged.into_values().map(|(_, entry)| entry).collect();
+    entries.push(PackEntry::from_memory(FINGERPRINT_FILE, fingerprint.into_bytes()));
+
+    // Patch the installed pack when possible; unchanged files stay where they are
+    if options.incremental && destination_vpk.exists() {
+        match vpk_utils::patch_vpk(
+            entries.clone(),
+            &destination_vpk,
+            &pack_options,
+            options.max_fragmentation,
+            &get_install_journal_path(),
+        ) {
+            Ok(Some(patch)) => {
+                let _ = fs::remove_dir_all(&lite_dir);
+                let mut msg = format!(
+                    "¡Mods actualizados correctamente!\n{} archivos reutilizados, {} escritos ({:.1} MB)",
+                    patch.reused_files,
+                    patch.written_files,
+                    patch.bytes_appended as f64 / (1024.0 * 1024.0)
+                );
+                push_lite_message(&mut msg, &lite_savings);
+                push_stripped_message(&mut msg, &stripped_files);
+                push_rejected_message(&mut msg, &rejected_files);
+                push_warnings_message(&mut msg, &warnings);
+                push_conflicts_message(&mut msg, &conflicts);
+                let mut result = MergeResult::ok(msg);
+                result.lite_savings = lite_savings;
+                result.stripped_files = stripped_files;
+                result.rejected_files = rejected_files;
+                result.warnings = warnings;
+                result.conflicts = conflicts;
+                result.conflict_counts = conflict_counts;
+                return Ok(result);
+            }
+            Ok(None) => println!("El pack instalado no se puede actualizar, se reconstruirá."),
+            Err(e) => eprintln!("Error actualizando el pack, se reconstruirá: {}", e),
         }
     }
 
-    // 2. Compile into single VPK (Native)
-    let generated_vpk = workshop_path.join(format!("{}.vpk", TEMP_NAME));
-    vpk_utils::pack_vpk_v1(&temp_path, &generated_vpk)?;
+    // 2. Compile into single VPK (Native), copying data directly from the sources
+    // The pack is built in the app's scratch folder; only the install step touches the game
+    fs::create_dir_all(&scratch_path).map_err(|e| format!("Error creando carpeta temporal: {}", e))?;
+    let generated_vpk = scratch_path.join(format!("{}.vpk", TEMP_NAME));
+    let packed = vpk_utils::pack_vpk(entries, &generated_vpk, &pack_options);
+    let _ = fs::remove_dir_all(&lite_dir);
+    let stats = match packed {
+        Ok(stats) => stats,
+        Err(e) => {
+            let _ = vpk_utils::remove_vpk_set(&generated_vpk);
+            return Err(e);
+        }
+    };
 
-    // 3. Move generated VPK to mods folder
-    let mods_path = get_mods_path();
-    let destination_vpk = mods_path.join(format!("{}.vpk", TEMP_NAME));
+    // Never install a pack that doesn't read back correctly
+    let report = vpk_utils::verify_vpk(&generated_vpk);
+    if !report.ok {
+        let _ = vpk_utils::remove_vpk_set(&generated_vpk);
+        let first_problem = report
+            .errors
+            .first()
+            .cloned()
+            .or_else(|| {
+                report
+                    .files
+                    .iter()
+                    .find(|file| file.status != "ok")
+                    .map(|file| format!("{} ({})", file.path, file.status))
+            })
+            .unwrap_or_default();
+        return Ok(MergeResult::error(format!(
+            "Error: El VPK generado no pasó la verificación.\n{}",
+            first_problem
+        )));
+    }
 
+    // 3. Move generated VPK to mods folder
     // Ensure mods folder exists
     if !mods_path.exists() {
         fs::create_dir_all(&mods_path)
@@ -304,26 +473,37 @@ pub fn merge_mods(ids: Vec<String>) -> Result<MergeResult, String> {
     }
 
     if generated_vpk.exists() {
-        // Remove existing destination if present
-        if destination_vpk.exists() {
-            fs::remove_file(&destination_vpk)
-                .map_err(|e| format!("Error eliminando VPK anterior: {}", e))?;
+        // Replace the existing set (pak01_dir.vpk + pak01_NNN.vpk chunks) in one transaction;
+        // the previous pack is restored if any step fails
+        if let Err(e) = install::install_vpk_set(&generated_vpk, &destination_vpk, &get_install_journal_path()) {
+            let _ = vpk_utils::remove_vpk_set(&generated_vpk);
+            return Err(format!("Error instalando VPK: {}", e));
         }
 
-        // Move the VPK
-        fs::rename(&generated_vpk, &destination_vpk)
-            .map_err(|e| format!("Error moviendo VPK: {}", e))?;
-
-        // Clean up temp directory
-        if temp_path.exists() {
-            fs::remove_dir_all(&temp_path)
-                .map_err(|e| format!("Error limpiando directorio temporal: {}", e))?;
+        let mut msg = format!("¡Mods fusionados correctamente!\nUbicación: {}", mods_path.display());
+        if stats.deduplicated_files > 0 {
+            msg.push_str(&format!(
+                "\n{} archivos duplicados compartidos ({:.1} MB ahorrados)",
+                stats.deduplicated_files,
+                stats.bytes_saved as f64 / (1024.0 * 1024.0)
+            ));
         }
 
-        Ok(MergeResult::ok(format!(
-            "¡Mods fusionados correctamente!\nUbicación: {}",
-            mods_path.display()
-        )))
+        push_lite_message(&mut msg, &lite_savings);
+        push_stripped_message(&mut msg, &stripped_files);
+        push_rejected_message(&mut msg, &rejected_files);
+        push_warnings_message(&mut msg, &warnings);
+        push_conflicts_message(&mut msg, &conflicts);
+
+        let mut result = MergeResult::ok(msg);
+        result.bytes_saved = stats.bytes_saved;
+        result.lite_savings = lite_savings;
+        result.stripped_files = stripped_files;
+        result.rejected_files = rejected_files;
+        result.warnings = warnings;
+        result.conflicts = conflicts;
+        result.conflict_counts = conflict_counts;
+        Ok(result)
     } else {
         Ok(MergeResult::error(
             "Error: No se generó el archivo VPK.",
@@ -331,5 +511,977 @@ pub fn merge_mods(ids: Vec<String>) -> Result<MergeResult, String> {
     }
 }
 
+/// Dry run of `merge_mods`: resolves which file each path would come from and
+/// returns the plan without extracting, building or installing anything.
+///
+/// Takes the same `ids` and `options`. Lite mode is not simulated, since it needs
+/// to read every texture. Only the mod cache's file tables may be updated.
+#[tauri::command]
+pub fn plan_merge(ids: Vec<String>, options: Option<MergeOptions>) -> Result<MergePlan, String> {
+    let options = options.unwrap_or_default();
+    // A preview must not touch settings.json: the order is synced in memory only
+    let (ids, rules) = Settings::load().ordered_selection(&ids, &workshop_mod_ids());
+    let resolution = resolve_merge(&ids, &options, &rules, &get_workshop_path());
+
+    let mut warnings = resolution.warnings;
+    warnings.extend(resolution.refusals);
+    if let Err(msg) = preflight(
+        &resolution.merged,
+        &options,
+        resolution.indexed_size,
+        &get_scratch_path(),
+        &get_mods_path(),
+    ) {
+        warnings.push(msg);
+    }
+
+    let estimated_size = estimated_pack_size(&resolution.merged);
+    let mut files: Vec<PlannedFile> = resolution
+        .merged
+        .into_iter()
+        .map(|(path, (mod_index, entry))| PlannedFile {
+            path,
+            mod_id: ids[mod_index].clone(),
+            size: entry.size,
+        })
+        .collect();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    println!("[OK] Plan de fusión: {} archivos ({:.1} MB)", files.len(), estimated_size as f64 / (1024.0 * 1024.0));
+    Ok(MergePlan {
+        files,
+        conflicts: resolution.conflicts,
+        conflict_counts: resolution.conflict_counts,
+        skipped_mods: resolution.skipped_mods,
+        stripped_files: resolution.stripped_files,
+        rejected_files: resolution.rejected_files,
+        estimated_size,
+        warnings,
+    })
+}
+
+/// Returns the winner rules saved for the selection made of `ids` (in any order).
+#[tauri::command]
+pub fn get_winner_rules(ids: Vec<String>) -> Result<Vec<WinnerRule>, String> {
+    Ok(Settings::load().winner_rules_for(&ids))
+}
+
+/// Replaces the winner rules of the selection made of `ids`; an empty list removes them.
+///
+/// Rules are checked in order and the first one matching a path decides its winner.
+/// Patterns are normalized like merged paths (lowercase, forward slashes). Returns
+/// the rules as saved.
+#[tauri::command]
+pub fn set_winner_rules(ids: Vec<String>, rules: Vec<WinnerRule>) -> Result<Vec<WinnerRule>, String> {
+    let mut saved: Vec<WinnerRule> = Vec::with_capacity(rules.len());
+    for rule in rules {
+        let pattern = rule.pattern.replace('\\', "/").trim_matches('/').to_lowercase();
+        if pattern.is_empty() {
+            return Err("Error: La regla no tiene ruta.".to_string());
+        }
+        if !ids.contains(&rule.mod_id) {
+            return Err(format!(
+                "Error: La regla '{}' usa el mod {}, que no está en la selección.",
+                pattern, rule.mod_id
+            ));
+        }
+        saved.push(WinnerRule {
+            pattern,
+            mod_id: rule.mod_id,
+        });
+    }
+
+    let key = settings::selection_key(&ids);
+    Settings::update(|settings| {
+        if saved.is_empty() {
+            settings.winner_rules.remove(&key);
+        } else {
+            settings.winner_rules.insert(key, saved.clone());
+        }
+    })
+    .map_err(|e| format!("Error guardando reglas: {}", e))?;
+
+    println!("[OK] {} reglas de prioridad guardadas", saved.len());
+    Ok(saved)
+}
+
+/// Returns the load order of the installed Workshop mods, highest priority first.
+///
+/// Mods installed since the last call are added at the bottom, by ID.
+#[tauri::command]
+pub fn get_load_order() -> Result<Vec<String>, String> {
+    Settings::update(|settings| {
+        settings.sync_load_order(&workshop_mod_ids());
+        settings.load_order.clone()
+    })
+    .map_err(|e| format!("Error guardando el orden de carga: {}", e))
+}
+
+/// Moves a mod in the load order: `direction` is "up", "down" or "top".
+/// Returns the new order, highest priority first.
+#[tauri::command]
+pub fn move_mod(mod_id: String, direction: String) -> Result<Vec<String>, String> {
+    let installed = workshop_mod_ids();
+    let moved = Settings::update(|settings| {
+        settings.sync_load_order(&installed);
+        settings.move_mod(&mod_id, &direction).map(|_| settings.load_order.clone())
+    })
+    .map_err(|e| format!("Error guardando el orden de carga: {}", e))??;
+
+    println!("[OK] {} movido ({})", mod_id, direction);
+    Ok(moved)
+}
+
+/// Syncs the saved load order with the Workshop folder and returns the selection in
+/// merge order (lowest priority first) along with its winner rules. If the settings
+/// can't be saved, the synced order is still used for this merge.
+fn ordered_selection(ids: &[String]) -> (Vec<String>, Vec<WinnerRule>) {
+    let installed = workshop_mod_ids();
+    Settings::update(|settings| settings.ordered_selection(ids, &installed)).unwrap_or_else(|e| {
+        eprintln!("Error guardando el orden de carga: {}", e);
+        Settings::load().ordered_selection(ids, &installed)
+    })
+}
+
+/// IDs of the VPKs in the Workshop folder.
+fn workshop_mod_ids() -> Vec<String> {
+    let Ok(read_dir) = fs::read_dir(get_workshop_path()) else {
+        return Vec::new();
+    };
+    read_dir
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("vpk"))
+        .filter_map(|path| path.file_stem().and_then(|s| s.to_str()).map(str::to_string))
+        .filter(|mod_id| !mod_id.is_empty())
+        .collect()
+}
+
+/// Entries chosen for a merge, plus everything that was left out or overridden.
+struct Resolution {
+    /// Each path maps to the index (in `ids`) of the mod that provides it and its entry
+    merged: HashMap<String, (usize, PackEntry)>,
+    stripped_files: Vec<StrippedFile>,
+    rejected_files: Vec<RejectedFile>,
+    warnings: Vec<String>,
+    skipped_mods: Vec<SkippedMod>,
+    conflicts: Vec<MergeConflict>,
+    conflict_counts: Vec<ModConflictCount>,
+    /// Total size of every file in the indexed VPKs, left-out files included
+    indexed_size: u64,
+    /// Why the merge can't go ahead: files over `options.max_entry_size`, which are
+    /// left out of `merged`
+    refusals: Vec<String>,
+}
+
+/// Indexes the selected VPKs in parallel (reusing cached file tables) and merges their
+/// entries by path in the order of `ids` (lowest priority first), except where a winner
+/// rule pins the mod to use. Nothing is read beyond the directory trees.
+fn resolve_merge(
+    ids: &[String],
+    options: &MergeOptions,
+    rules: &[WinnerRule],
+    workshop_path: &Path,
+) -> Resolution {
+    let cache = ModCache::new(&get_mod_cache_path());
+    let indexed = vpk_utils::parallel_map(ids, || (), |_, mod_id| {
+        let vpk_path = workshop_path.join(format!("{}.vpk", mod_id));
+        if !vpk_path.exists() {
+            return None;
+        }
+        Some(cache.index_vpk(&vpk_path, false))
+    });
+
+    let mut merged: HashMap<String, (usize, PackEntry)> = HashMap::new();
+    let mut stripped_files: Vec<StrippedFile> = Vec::new();
+    let mut rejected_files: Vec<RejectedFile> = Vec::new();
+    let mut warnings: Vec<String> = Vec::new();
+    let mut skipped_mods: Vec<SkippedMod> = Vec::new();
+    let mut indexed_size: u64 = 0;
+    let mut refusals: Vec<String> = Vec::new();
+    // Path of each winning entry as stored in its VPK, before normalization
+    let mut stored_paths: HashMap<String, String> = HashMap::new();
+    let pins = winner_pins(rules, ids);
+    // Mods (indices in `ids`) whose entry for a path lost to another mod
+    let mut overridden: HashMap<String, Vec<usize>> = HashMap::new();
+    for (mod_index, (mod_id, entries)) in ids.iter().zip(indexed).enumerate() {
+        let entries = match entries {
+            Some(Ok(entries)) => entries,
+            Some(Err(e)) => {
+                eprintln!("Error leyendo {}: {}", mod_id, e);
+                skipped_mods.push(SkippedMod {
+                    mod_id: mod_id.clone(),
+                    reason: e,
+                });
+                continue; // Skip failed mods but try to continue
+            }
+            None => {
+                skipped_mods.push(SkippedMod {
+                    mod_id: mod_id.clone(),
+                    reason: "missing".to_string(),
+                });
+                continue;
+            }
+        };
+        indexed_size += entries.iter().map(|entry| entry.size).sum::<u64>();
+
+        for mut entry in entries {
+            // The engine looks files up case-insensitively, so `Models/X.mdl` and
+            // `models/x.mdl` must end up as the same entry
+            let stored_path = entry.normalize_path();
+
+            // Absolute paths and `..` could escape the game folder, so the whole entry is refused
+            if let Err(reason) = filters::check_entry_path(&stored_path) {
+                println!("[AVISO] Ruta no válida en {}: {:?} ({})", mod_id, stored_path, reason);
+                rejected_files.push(RejectedFile {
+                    mod_id: mod_id.clone(),
+                    path: stored_path,
+                    reason: reason.to_string(),
+                });
+                continue;
+            }
+
+            // Root files like addoninfo.txt should NOT be included in merged VPK
+            if entry.dir == " " || entry.dir.is_empty() {
+                continue;
+            }
+            // Authoring files, readmes and thumbnails the game never loads
+            let path = entry.full_path();
+            if filters::is_stripped(&path, &entry.ext, &options.strip_extensions, &options.strip_patterns) {
+                stripped_files.push(StrippedFile {
+                    mod_id: mod_id.clone(),
+                    path,
+                    size: entry.size,
+                });
+                continue;
+            }
+
+            // A hostile VPK can claim absurd sizes; refuse before anything is read
+            if options.max_entry_size > 0 && entry.size > options.max_entry_size {
+                refusals.push(format!(
+                    "'{}' en {} ocupa {:.1} MB, más que el límite por archivo ({:.1} MB).",
+                    stored_path,
+                    mod_id,
+                    entry.size as f64 / (1024.0 * 1024.0),
+                    options.max_entry_size as f64 / (1024.0 * 1024.0)
+                ));
+                continue;
+            }
+
+            // A pinned mod keeps its file even when later mods ship the same path
+            if let Some((previous_index, _)) = merged.get(&path) {
+                if *previous_index != mod_index && pinned_mod(&pins, &path) == Some(*previous_index) {
+                    let previous_mod = &ids[*previous_index];
+                    let previous = (stored_paths[&path].as_str(), previous_mod.as_str());
+                    warn_path_collision(&mut warnings, previous, (&stored_path, mod_id), previous_mod);
+                    overridden.entry(path).or_default().push(mod_index);
+                    continue;
+                }
+            }
+
+            if let Some(previous_path) = stored_paths.insert(path.clone(), stored_path.clone()) {
+                let previous_mod = merged.get(&path).map(|(index, _)| ids[*index].as_str()).unwrap_or("");
+                warn_path_collision(&mut warnings, (&previous_path, previous_mod), (&stored_path, mod_id), mod_id);
+            }
+            if let Some((previous_index, _)) = merged.insert(path.clone(), (mod_index, entry)) {
+                if previous_index != mod_index {
+                    overridden.entry(path).or_default().push(previous_index);
+                }
+            }
+        }
+    }
+
+    let (conflicts, conflict_counts) = conflict_report(&merged, &overridden, &pins, ids);
+    if !conflicts.is_empty() {
+        println!("[AVISO] {} archivos presentes en varios mods", conflicts.len());
+    }
+
+    Resolution {
+        merged,
+        stripped_files,
+        rejected_files,
+        warnings,
+        skipped_mods,
+        conflicts,
+        conflict_counts,
+        indexed_size,
+        refusals,
+    }
+}
+
+/// Warns when two mods ship the same entry under paths that differ only in case or
+/// separators, which collapse into one once normalized. `previous` and `current` are
+/// (stored path, mod ID); `kept_mod` is the mod whose file is used.
+fn warn_path_collision(warnings: &mut Vec<String>, previous: (&str, &str), current: (&str, &str), kept_mod: &str) {
+    let ((previous_path, previous_mod), (current_path, current_mod)) = (previous, current);
+    if previous_path == current_path {
+        return;
+    }
+    let separators = |path: &str| path.replace('\\', "/").trim_matches('/').to_string();
+    let difference = if separators(previous_path) == separators(current_path) {
+        "las barras"
+    } else if previous_path.eq_ignore_ascii_case(current_path) {
+        "mayúsculas"
+    } else {
+        "mayúsculas y barras"
+    };
+    let warning = format!(
+        "'{}' ({}) y '{}' ({}) solo difieren en {}; se usa el de {}",
+        previous_path, previous_mod, current_path, current_mod, difference, kept_mod
+    );
+    println!("[AVISO] {}", warning);
+    warnings.push(warning);
+}
+
+/// Lite mode: rewrites every merged VTF texture without its largest mip levels.
+///
+/// Shrunk textures are written to `lite_dir` and their entries repointed there.
+/// Returns the bytes saved per mod, in selection order.
+fn apply_lite_textures(
+    merged: &mut HashMap<String, (usize, PackEntry)>,
+    ids: &[String],
+    options: &MergeOptions,
+    lite_dir: &Path,
+) -> Result<Vec<LiteSavings>, String> {
+    fs::create_dir_all(lite_dir).map_err(|e| format!("Error creando carpeta temporal: {}", e))?;
 
+    let textures: Vec<String> = merged
+        .iter()
+        .filter(|(_, (_, entry))| entry.ext.eq_ignore_ascii_case("vtf"))
+        .map(|(path, _)| path.clone())
+        .collect();
+
+    let shrunk = vpk_utils::parallel_map(&textures, || (), |_, path| {
+        let (_, entry) = &merged[path];
+        let mut data = Vec::with_capacity(entry.size as usize);
+        vpk_utils::copy_entry(entry, &mut data)?;
+        let Some(lite) = vtf::drop_mip_levels(&data, options.lite_drop_mips, options.lite_min_size) else {
+            return Ok(None);
+        };
+
+        let lite_path = lite_dir.join(format!("{:x}.vtf", Md5::digest(path.as_bytes())));
+        fs::write(&lite_path, &lite).map_err(|e| format!("Error escribiendo {}: {}", lite_path.display(), e))?;
+        Ok::<_, String>(Some((lite_path, lite.len() as u64, crc32fast::hash(&lite))))
+    });
+
+    let mut savings: Vec<LiteSavings> = Vec::new();
+    for (path, result) in textures.iter().zip(shrunk) {
+        let Some((lite_path, size, crc)) = result? else {
+            continue;
+        };
+        let (mod_index, entry) = merged.get_mut(path).expect("texture comes from the merged map");
+        let saved = entry.size - size;
+        entry.source = EntrySource::Blob(lite_path);
+        entry.size = size;
+        entry.crc = Some(crc);
+        entry.md5 = None;
+
+        let mod_id = &ids[*mod_index];
+        match savings.iter_mut().find(|savings| &savings.mod_id == mod_id) {
+            Some(existing) => {
+                existing.textures += 1;
+                existing.bytes_saved += saved;
+            }
+            None => savings.push(LiteSavings {
+                mod_id: mod_id.clone(),
+                textures: 1,
+                bytes_saved: saved,
+            }),
+        }
+    }
+
+    savings.sort_by_key(|savings| ids.iter().position(|id| *id == savings.mod_id));
+    for mod_savings in &savings {
+        println!(
+            "[OK] Lite {}: {} texturas reducidas ({} bytes ahorrados)",
+            mod_savings.mod_id, mod_savings.textures, mod_savings.bytes_saved
+        );
+    }
+    Ok(savings)
+}
 
+/// Copies the files of the selected mods into the mod cache's blob store.
+/// Failures are only logged: the merge reads from the source VPKs either way.
+fn store_mod_blobs(ids: &[String], workshop_path: &Path) {
+    let cache = ModCache::new(&get_mod_cache_path());
+    let stored = vpk_utils::parallel_map(ids, || (), |_, mod_id| {
+        let vpk_path = workshop_path.join(format!("{}.vpk", mod_id));
+        if !vpk_path.exists() {
+            return Ok(());
+        }
+        cache.index_vpk(&vpk_path, true).map(|_| ())
+    });
+    for (mod_id, result) in ids.iter().zip(stored) {
+        if let Err(e) = result {
+            eprintln!("Error guardando {} en caché: {}", mod_id, e);
+        }
+    }
+}
+
+/// Space left free on each volume on top of the pack itself.
+const FREE_SPACE_MARGIN: u64 = 64 * 1024 * 1024;
+
+/// Estimates the size of the merged pack from the source trees and checks it against
+/// `options.max_total_size` and the free space of the scratch and game volumes.
+/// With `options.cache_blobs`, the scratch volume must also fit `indexed_size`, the
+/// selected mods' files copied into the mod cache.
+///
+/// The estimate ignores deduplication, so it is an upper bound. Returns the message
+/// to show when the merge can't go ahead.
+fn preflight(
+    merged: &HashMap<String, (usize, PackEntry)>,
+    options: &MergeOptions,
+    indexed_size: u64,
+    scratch_path: &Path,
+    mods_path: &Path,
+) -> Result<(), String> {
+    let expected = estimated_pack_size(merged);
+    let megabytes = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+
+    if options.max_total_size > 0 && expected > options.max_total_size {
+        return Err(format!(
+            "El pack ocuparía {:.1} MB, más que el límite configurado ({:.1} MB).",
+            megabytes(expected),
+            megabytes(options.max_total_size)
+        ));
+    }
+
+    // Lite textures are staged next to the pack before it is built
+    let lite_size: u64 = if options.lite_textures {
+        merged
+            .values()
+            .filter(|(_, entry)| entry.ext.eq_ignore_ascii_case("vtf"))
+            .map(|(_, entry)| entry.size)
+            .sum()
+    } else {
+        0
+    };
+
+    // The mod cache shares the app cache folder, and so the volume, with the scratch space
+    let blob_size = if options.cache_blobs { indexed_size } else { 0 };
+
+    let checks = [
+        (scratch_path, expected + lite_size + blob_size, "la carpeta temporal"),
+        (mods_path, expected, "la carpeta del juego"),
+    ];
+    for (path, needed, what) in checks {
+        let Some(available) = available_space(path) else {
+            continue; // Unknown free space shouldn't block the merge
+        };
+        if available < needed + FREE_SPACE_MARGIN {
+            return Err(format!(
+                "No hay espacio suficiente en {} ({}): se necesitan {:.1} MB y hay {:.1} MB libres.",
+                what,
+                path.display(),
+                megabytes(needed + FREE_SPACE_MARGIN),
+                megabytes(available)
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Size of a pack holding every merged entry, without deduplication.
+fn estimated_pack_size(merged: &HashMap<String, (usize, PackEntry)>) -> u64 {
+    let data_size: u64 = merged.values().map(|(_, entry)| entry.size).sum();
+    // Each tree entry takes its path plus a fixed 18-byte record and terminators
+    let tree_size: u64 = merged.keys().map(|path| path.len() as u64 + 21).sum();
+    data_size + tree_size
+}
+
+/// Free bytes on the volume holding `path`, looking at the closest existing ancestor.
+fn available_space(path: &Path) -> Option<u64> {
+    let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+    fs2::available_space(existing).ok()
+}
+
+/// Appends the lite mode summary to a merge message.
+fn push_lite_message(msg: &mut String, savings: &[LiteSavings]) {
+    if savings.is_empty() {
+        return;
+    }
+    let textures: usize = savings.iter().map(|savings| savings.textures).sum();
+    let bytes: u64 = savings.iter().map(|savings| savings.bytes_saved).sum();
+    msg.push_str(&format!(
+        "\nModo Lite: {} texturas reducidas ({:.1} MB ahorrados)",
+        textures,
+        bytes as f64 / (1024.0 * 1024.0)
+    ));
+}
+
+/// Appends the strip list summary to a merge message.
+fn push_stripped_message(msg: &mut String, stripped_files: &[StrippedFile]) {
+    if stripped_files.is_empty() {
+        return;
+    }
+    let bytes: u64 = stripped_files.iter().map(|file| file.size).sum();
+    msg.push_str(&format!(
+        "\n{} archivos innecesarios omitidos ({:.1} MB)",
+        stripped_files.len(),
+        bytes as f64 / (1024.0 * 1024.0)
+    ));
+}
+
+/// Appends the files refused for unsafe paths to a merge message.
+fn push_rejected_message(msg: &mut String, rejected_files: &[RejectedFile]) {
+    if rejected_files.is_empty() {
+        return;
+    }
+    msg.push_str(&format!("\n{} archivos con rutas no válidas rechazados:", rejected_files.len()));
+    for file in rejected_files {
+        msg.push_str(&format!("\n  {} → {}", file.mod_id, file.path));
+    }
+}
+
+/// Appends the number of warnings to a merge message.
+fn push_warnings_message(msg: &mut String, warnings: &[String]) {
+    if !warnings.is_empty() {
+        msg.push_str(&format!("\n{} avisos (ver detalles)", warnings.len()));
+    }
+}
+
+/// Appends the number of files shipped by several mods to a merge message.
+fn push_conflicts_message(msg: &mut String, conflicts: &[MergeConflict]) {
+    if !conflicts.is_empty() {
+        msg.push_str(&format!(
+            "\n{} archivos presentes en varios mods; se usa el del mod fijado por regla o con más prioridad (ver detalles)",
+            conflicts.len()
+        ));
+    }
+}
+
+/// Lists every path provided by more than one mod, sorted by path, and how many
+/// of those each mod won and lost (lowest priority first).
+fn conflict_report(
+    merged: &HashMap<String, (usize, PackEntry)>,
+    overridden: &HashMap<String, Vec<usize>>,
+    pins: &[(String, usize)],
+    ids: &[String],
+) -> (Vec<MergeConflict>, Vec<ModConflictCount>) {
+    let mut won = vec![0usize; ids.len()];
+    let mut lost = vec![0usize; ids.len()];
+    let mut conflicts: Vec<MergeConflict> = Vec::new();
+    for (path, losers) in overridden {
+        let Some((winner, _)) = merged.get(path) else {
+            continue;
+        };
+        won[*winner] += 1;
+        for loser in losers {
+            lost[*loser] += 1;
+        }
+        conflicts.push(MergeConflict {
+            path: path.clone(),
+            winner: ids[*winner].clone(),
+            losers: losers.iter().map(|loser| ids[*loser].clone()).collect(),
+            pinned: pinned_mod(pins, path) == Some(*winner),
+        });
+    }
+    conflicts.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let counts = ids
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| won[*index] + lost[*index] > 0)
+        .map(|(index, mod_id)| ModConflictCount {
+            mod_id: mod_id.clone(),
+            won: won[index],
+            lost: lost[index],
+        })
+        .collect();
+    (conflicts, counts)
+}
+
+/// Winner rules whose mod is part of the selection, as (pattern, index in `ids`).
+fn winner_pins(rules: &[WinnerRule], ids: &[String]) -> Vec<(String, usize)> {
+    rules
+        .iter()
+        .filter_map(|rule| {
+            let mod_index = ids.iter().position(|mod_id| *mod_id == rule.mod_id)?;
+            Some((rule.pattern.clone(), mod_index))
+        })
+        .collect()
+}
+
+/// Mod pinned for `path` by the first matching rule, if any.
+fn pinned_mod(pins: &[(String, usize)], path: &str) -> Option<usize> {
+    pins.iter()
+        .find(|(pattern, _)| filters::glob_match(pattern, path))
+        .map(|(_, mod_index)| *mod_index)
+}
+
+/// Fingerprint of a merge request: ordered IDs, options, winner rules and each
+/// source VPK's size, modification time and tree hash.
+fn merge_fingerprint(ids: &[String], options: &MergeOptions, rules: &[WinnerRule], workshop_path: &Path) -> String {
+    let mut description = serde_json::to_string(options).unwrap_or_default();
+    description.push_str(&serde_json::to_string(rules).unwrap_or_default());
+    for mod_id in ids {
+        let vpk_path = workshop_path.join(format!("{}.vpk", mod_id));
+        let source = vpk_utils::source_fingerprint(&vpk_path).unwrap_or_else(|_| "missing".to_string());
+        description.push_str(&format!("\n{}|{}", mod_id, source));
+    }
+    format!("{:x}", Md5::digest(description.as_bytes()))
+}
+
+/// Reads the fingerprint stored in an installed pack, if the pack and all of its
+/// numbered archives are present.
+fn installed_fingerprint(pack_path: &Path) -> Option<String> {
+    let archive = VpkArchive::open(pack_path).ok()?;
+    let archive_indexes: HashSet<u16> = archive
+        .entries
+        .iter()
+        .filter(|entry| !entry.is_embedded() && entry.length > 0)
+        .map(|entry| entry.archive_index)
+        .collect();
+    if !archive_indexes
+        .into_iter()
+        .all(|index| vpk_utils::archive_path(pack_path, index).exists())
+    {
+        return None;
+    }
+    let data = archive.read_file(FINGERPRINT_FILE, SMALL_FILE_MAX_SIZE).ok()?;
+    Some(String::from_utf8_lossy(&data).trim().to_string())
+}
+
+/// Verifies the integrity of a VPK and returns a per-file report.
+///
+/// Without `mod_id` the installed merged pack (mods/pak01_dir.vpk) is checked;
+/// otherwise the Workshop VPK with that ID.
+#[tauri::command]
+pub fn verify_vpk(mod_id: Option<String>) -> Result<VerifyReport, String> {
+    let vpk_path = match mod_id {
+        Some(mod_id) => get_workshop_path().join(format!("{}.vpk", mod_id)),
+        None => get_mods_path().join(format!("{}.vpk", TEMP_NAME)),
+    };
+
+    if !vpk_path.exists() {
+        return Err(format!("No se encontró el VPK: {}", vpk_path.display()));
+    }
+
+    let report = vpk_utils::verify_vpk(&vpk_path);
+    println!(
+        "[{}] Verificación de {:?}: {} archivos, {} con errores",
+        if report.ok { "OK" } else { "ERROR" },
+        vpk_path,
+        report.file_count,
+        report.bad_files
+    );
+    Ok(report)
+}
+
+/// Extracts a Workshop mod, or the installed merged pack when `mod_id` is omitted,
+/// into a folder chosen by the user.
+///
+/// Root-level files like addoninfo.txt are only written when `include_root_files` is set.
+/// Emits "extract-progress" events while working; the command runs off the main thread
+/// so they reach the window before the extraction finishes.
+#[tauri::command(async)]
+pub fn extract_mod(
+    window: tauri::Window,
+    mod_id: Option<String>,
+    out_dir: String,
+    include_root_files: bool,
+) -> Result<ExtractSummary, String> {
+    let vpk_path = match mod_id {
+        Some(mod_id) => get_workshop_path().join(format!("{}.vpk", mod_id)),
+        None => get_mods_path().join(format!("{}.vpk", TEMP_NAME)),
+    };
+
+    if !vpk_path.exists() {
+        return Err(format!("No se encontró el VPK: {}", vpk_path.display()));
+    }
+
+    let out_dir = Path::new(&out_dir);
+    fs::create_dir_all(out_dir).map_err(|e| format!("No se pudo crear la carpeta destino: {}", e))?;
+    println!("Extrayendo {:?} en {:?}", vpk_path, out_dir);
+
+    // Emit at most ~100 progress events regardless of the number of files
+    let mut last_percent = None;
+    let summary = vpk_utils::extract_vpk(&vpk_path, out_dir, include_root_files, |done, total| {
+        let percent = done * 100 / total.max(1);
+        if last_percent != Some(percent) {
+            last_percent = Some(percent);
+            let _ = window.emit("extract-progress", ExtractProgress { done, total });
+        }
+    })
+    .map_err(|e| format!("Error extrayendo VPK: {}", e))?;
+
+    println!(
+        "[OK] {} archivos extraídos ({} bytes) en {:?}",
+        summary.files_written, summary.bytes_written, out_dir
+    );
+    for path in &summary.rejected_paths {
+        println!("[AVISO] Ruta no válida omitida: {:?}", path);
+    }
+    Ok(summary)
+}
+
+/// Reports how much disk space the mod cache uses.
+#[tauri::command]
+pub fn get_cache_size() -> Result<CacheReport, String> {
+    Ok(ModCache::new(&get_mod_cache_path()).report())
+}
+
+/// Prunes the mod cache and returns what remains.
+///
+/// Removes file tables of Workshop VPKs that were deleted or updated and any blob
+/// no longer referenced. With `all`, the whole cache is cleared.
+#[tauri::command]
+pub fn prune_cache(all: Option<bool>) -> Result<CacheReport, String> {
+    let cache = ModCache::new(&get_mod_cache_path());
+    let before = cache.report().total_bytes;
+    let report = cache
+        .prune(all.unwrap_or(false))
+        .map_err(|e| format!("Error limpiando caché: {}", e))?;
+    println!(
+        "[OK] Caché limpiada: {} bytes liberados",
+        before.saturating_sub(report.total_bytes)
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    /// Packs `files` (path, contents) into a single-file VPK at `path`.
+    fn write_vpk(path: &Path, files: &[(&str, &[u8])]) {
+        let entries = files
+            .iter()
+            .map(|(file_path, data)| PackEntry::from_memory(file_path, data.to_vec()))
+            .collect();
+        vpk_utils::pack_vpk(entries, path, &vpk_utils::PackOptions::default()).unwrap();
+    }
+
+    fn ids(mod_ids: &[&str]) -> Vec<String> {
+        mod_ids.iter().map(|mod_id| mod_id.to_string()).collect()
+    }
+
+    #[test]
+    fn fingerprint_changes_with_options_order_rules_and_sources() {
+        let dir = TestDir::new("fingerprint");
+        write_vpk(&dir.0.join("a.vpk"), &[("models/a.mdl", b"a")]);
+        write_vpk(&dir.0.join("b.vpk"), &[("models/b.mdl", b"b")]);
+        let selection = ids(&["a", "b"]);
+        let options = MergeOptions::default();
+        let base = merge_fingerprint(&selection, &options, &[], &dir.0);
+        assert_eq!(base, merge_fingerprint(&selection, &options, &[], &dir.0));
+
+        let lite = MergeOptions {
+            lite_textures: true,
+            ..Default::default()
+        };
+        assert_ne!(base, merge_fingerprint(&selection, &lite, &[], &dir.0));
+        assert_ne!(base, merge_fingerprint(&ids(&["b", "a"]), &options, &[], &dir.0));
+        let rules = [WinnerRule {
+            pattern: "models/*".to_string(),
+            mod_id: "a".to_string(),
+        }];
+        assert_ne!(base, merge_fingerprint(&selection, &options, &rules, &dir.0));
+
+        // Same contents, newer modification time
+        let later = SystemTime::now() + Duration::from_secs(60);
+        let source = File::options().write(true).open(dir.0.join("b.vpk")).unwrap();
+        source.set_modified(later).unwrap();
+        drop(source);
+        assert_ne!(base, merge_fingerprint(&selection, &options, &[], &dir.0));
+    }
+
+    /// A merged entry claiming `size` bytes; preflight never reads its contents.
+    fn sized_entry(path: &str, size: u64) -> (String, (usize, PackEntry)) {
+        let mut entry = PackEntry::from_memory(path, Vec::new());
+        entry.size = size;
+        (path.to_string(), (0, entry))
+    }
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn resolve_refuses_entries_over_the_size_limit() {
+        let dir = TestDir::new("resolve-entry-limit");
+        write_vpk(&dir.0.join("a.vpk"), &[("models/big.mdl", &[0u8; 2048]), ("models/small.mdl", b"ok")]);
+        let options = MergeOptions {
+            max_entry_size: 1024,
+            ..Default::default()
+        };
+        let resolution = resolve_merge(&ids(&["a"]), &options, &[], &dir.0);
+
+        assert_eq!(resolution.refusals.len(), 1);
+        assert!(resolution.refusals[0].starts_with("'models/big.mdl' en a ocupa"));
+        assert!(resolution.merged.contains_key("models/small.mdl"));
+        assert!(!resolution.merged.contains_key("models/big.mdl"));
+    }
+
+    #[test]
+    fn preflight_enforces_the_total_limit() {
+        let dir = TestDir::new("preflight-total");
+        let merged = HashMap::from([sized_entry("models/a.mdl", 3 * MB)]);
+        let options = MergeOptions {
+            max_total_size: 2 * MB,
+            ..Default::default()
+        };
+        let error = preflight(&merged, &options, 0, &dir.0, &dir.0).unwrap_err();
+        assert_eq!(error, "El pack ocuparía 3.0 MB, más que el límite configurado (2.0 MB).");
+
+        let options = MergeOptions {
+            max_total_size: 4 * MB,
+            ..Default::default()
+        };
+        assert!(preflight(&merged, &options, 0, &dir.0, &dir.0).is_ok());
+    }
+
+    #[test]
+    fn preflight_keeps_a_free_space_margin() {
+        let dir = TestDir::new("preflight-space");
+        let available = available_space(&dir.0).unwrap();
+        assert!(available > 4 * FREE_SPACE_MARGIN, "not enough free space to run this test");
+        let options = MergeOptions {
+            max_total_size: 0,
+            ..Default::default()
+        };
+
+        // Fits on the volume, but not with the margin on top
+        let tight = HashMap::from([sized_entry("models/a.mdl", available - FREE_SPACE_MARGIN / 2)]);
+        let error = preflight(&tight, &options, 0, &dir.0, &dir.0).unwrap_err();
+        assert!(error.starts_with("No hay espacio suficiente en la carpeta temporal"));
+
+        let roomy = HashMap::from([sized_entry("models/a.mdl", available - 2 * FREE_SPACE_MARGIN)]);
+        assert!(preflight(&roomy, &options, FREE_SPACE_MARGIN * 3 / 2, &dir.0, &dir.0).is_ok());
+
+        // Cached blobs land on the scratch volume too
+        let cached = MergeOptions {
+            cache_blobs: true,
+            ..options
+        };
+        let error = preflight(&roomy, &cached, FREE_SPACE_MARGIN * 3 / 2, &dir.0, &dir.0).unwrap_err();
+        assert!(error.starts_with("No hay espacio suficiente en la carpeta temporal"));
+    }
+
+    /// Workshop folder with three mods overlapping on `models/`.
+    fn overlapping_mods(name: &str) -> TestDir {
+        let dir = TestDir::new(name);
+        write_vpk(
+            &dir.0.join("a.vpk"),
+            &[("models/shared.mdl", b"a"), ("models/ab.mdl", b"a"), ("models/only_a.mdl", b"a")],
+        );
+        write_vpk(
+            &dir.0.join("b.vpk"),
+            &[("models/shared.mdl", b"b"), ("models/ab.mdl", b"b"), ("models/bc.mdl", b"b")],
+        );
+        write_vpk(&dir.0.join("c.vpk"), &[("models/shared.mdl", b"c"), ("models/bc.mdl", b"c")]);
+        dir
+    }
+
+    fn winners(resolution: &Resolution) -> Vec<(&str, &str, Vec<&str>, bool)> {
+        resolution
+            .conflicts
+            .iter()
+            .map(|conflict| {
+                let losers = conflict.losers.iter().map(String::as_str).collect();
+                (conflict.path.as_str(), conflict.winner.as_str(), losers, conflict.pinned)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_the_winner_of_each_overlapping_path() {
+        let dir = overlapping_mods("conflicts");
+        let resolution = resolve_merge(&ids(&["a", "b", "c"]), &MergeOptions::default(), &[], &dir.0);
+
+        assert_eq!(
+            winners(&resolution),
+            [
+                ("models/ab.mdl", "b", vec!["a"], false),
+                ("models/bc.mdl", "c", vec!["b"], false),
+                ("models/shared.mdl", "c", vec!["a", "b"], false),
+            ]
+        );
+        let counts: Vec<_> = resolution
+            .conflict_counts
+            .iter()
+            .map(|count| (count.mod_id.as_str(), count.won, count.lost))
+            .collect();
+        assert_eq!(counts, [("a", 0, 2), ("b", 1, 2), ("c", 2, 0)]);
+        assert_eq!(resolution.merged["models/only_a.mdl"].0, 0);
+
+        let mut msg = String::new();
+        push_conflicts_message(&mut msg, &resolution.conflicts);
+        assert_eq!(
+            msg,
+            "\n3 archivos presentes en varios mods; se usa el del mod fijado por regla o con más prioridad (ver detalles)"
+        );
+    }
+
+    fn rule(pattern: &str, mod_id: &str) -> WinnerRule {
+        WinnerRule {
+            pattern: pattern.to_string(),
+            mod_id: mod_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn pinned_mod_beats_later_mods() {
+        let dir = overlapping_mods("pins");
+        let rules = [rule("models/shared.mdl", "a")];
+        let resolution = resolve_merge(&ids(&["a", "b", "c"]), &MergeOptions::default(), &rules, &dir.0);
+
+        assert_eq!(resolution.merged["models/shared.mdl"].0, 0);
+        assert_eq!(
+            winners(&resolution),
+            [
+                ("models/ab.mdl", "b", vec!["a"], false),
+                ("models/bc.mdl", "c", vec!["b"], false),
+                ("models/shared.mdl", "a", vec!["b", "c"], true),
+            ]
+        );
+    }
+
+    #[test]
+    fn pins_for_unselected_mods_are_ignored() {
+        let selection = ids(&["a", "b", "c"]);
+        let rules = [rule("models/*", "z"), rule("models/shared.mdl", "b"), rule("models/*.mdl", "a")];
+        let pins = winner_pins(&rules, &selection);
+        assert_eq!(pins, [("models/shared.mdl".to_string(), 1), ("models/*.mdl".to_string(), 0)]);
+        // The first matching rule of a selected mod decides
+        assert_eq!(pinned_mod(&pins, "models/shared.mdl"), Some(1));
+        assert_eq!(pinned_mod(&pins, "models/ab.mdl"), Some(0));
+        assert_eq!(pinned_mod(&pins, "sound/x.wav"), None);
+
+        let dir = overlapping_mods("pins-unselected");
+        let resolution = resolve_merge(&selection, &MergeOptions::default(), &[rule("models/*", "z")], &dir.0);
+        let unpinned = resolve_merge(&selection, &MergeOptions::default(), &[], &dir.0);
+        assert_eq!(winners(&resolution), winners(&unpinned));
+        assert_eq!(resolution.merged["models/shared.mdl"].0, 2);
+    }
+
+    #[test]
+    fn path_collision_warnings_name_the_difference() {
+        let mut warnings = Vec::new();
+        warn_path_collision(&mut warnings, ("models/x.mdl", "a"), ("models/x.mdl", "b"), "b");
+        assert!(warnings.is_empty());
+
+        warn_path_collision(&mut warnings, ("Models/X.mdl", "a"), ("models/x.mdl", "b"), "b");
+        warn_path_collision(&mut warnings, ("models\\x.mdl", "a"), ("models/x.mdl", "b"), "a");
+        warn_path_collision(&mut warnings, ("Models\\x.mdl", "a"), ("models/x.mdl", "b"), "a");
+        assert_eq!(
+            warnings,
+            [
+                "'Models/X.mdl' (a) y 'models/x.mdl' (b) solo difieren en mayúsculas; se usa el de b",
+                "'models\\x.mdl' (a) y 'models/x.mdl' (b) solo difieren en las barras; se usa el de a",
+                "'Models\\x.mdl' (a) y 'models/x.mdl' (b) solo difieren en mayúsculas y barras; se usa el de a",
+            ]
+        );
+    }
+
+    #[test]
+    fn installed_fingerprint_reads_back_the_stored_one() {
+        let dir = TestDir::new("fingerprint-installed");
+        let pack = dir.0.join("pak01_dir.vpk");
+        assert_eq!(installed_fingerprint(&pack), None);
+
+        write_vpk(&pack, &[("models/a.mdl", b"a"), (FINGERPRINT_FILE, b"0123abcd\n")]);
+        assert_eq!(installed_fingerprint(&pack).as_deref(), Some("0123abcd"));
+    }
+}
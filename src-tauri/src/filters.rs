@@ -0,0 +1,157 @@
+//! Path filters applied while merging
+
+/// Authoring and editor files the game never loads.
+pub const DEFAULT_STRIP_EXTENSIONS: &[&str] = &[
+    // Image editors
+    "psd", "xcf", "kra", "pdn", "ai",
+    // 3D packages and model sources
+    "blend", "blend1", "max", "ma", "mb", "fbx", "obj", "3ds", "c4d",
+    "qc", "qci", "smd", "dmx", "vta", "vrd",
+    // Map sources and editor leftovers
+    "vmf", "vmx", "bak", "tmp", "log",
+    // Documentation
+    "md",
+];
+
+/// Documentation and OS metadata that slip into Workshop uploads.
+pub const DEFAULT_STRIP_PATTERNS: &[&str] = &[
+    "*readme*.txt",
+    "*/thumbs.db",
+    "*/desktop.ini",
+    "*/.ds_store",
+    "*thumb*.jpg",
+    "*thumb*.png",
+];
+
+/// Matches `path` against a glob `pattern`, ignoring ASCII case.
+/// `*` matches any run of characters (slashes included) and `?` exactly one.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_ascii_lowercase().chars().collect();
+    let path: Vec<char> = path.to_ascii_lowercase().chars().collect();
+
+    // Greedy matching with backtracking to the last `*`
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < path.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == path[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Returns true when a file should be left out of the merged pack: its extension is
+/// in `extensions` (without the dot, any case) or its path matches one of `patterns`.
+pub fn is_stripped(path: &str, ext: &str, extensions: &[String], patterns: &[String]) -> bool {
+    let ext = ext.trim_start_matches('.');
+    extensions
+        .iter()
+        .any(|stripped| stripped.trim_start_matches('.').eq_ignore_ascii_case(ext))
+        || patterns.iter().any(|pattern| glob_match(pattern, path))
+}
+
+/// Device names Windows reserves in every folder, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9",
+    "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Checks that a path stored in a VPK stays inside the folder it is extracted to.
+///
+/// Both `/` and `\` count as separators. On failure, returns why the path was
+/// rejected: "empty", "absolute_path", "parent_dir", "blank_name", "invalid_char"
+/// or "reserved_name".
+pub fn check_entry_path(path: &str) -> Result<(), &'static str> {
+    if path.is_empty() {
+        return Err("empty");
+    }
+    if path.starts_with(['/', '\\']) || path.as_bytes().get(1) == Some(&b':') {
+        return Err("absolute_path");
+    }
+    if path
+        .chars()
+        .any(|c| c.is_control() || matches!(c, ':' | '<' | '>' | '"' | '|' | '?' | '*'))
+    {
+        return Err("invalid_char");
+    }
+
+    for component in path.split(['/', '\\']) {
+        // Windows drops trailing dots and spaces, so ".. " and "..." mean ".." there,
+        // ". " means "." and a name made only of spaces means nothing at all
+        let trimmed = component.trim_end_matches(['.', ' ']);
+        if trimmed.is_empty() && !component.is_empty() {
+            match component.matches('.').count() {
+                0 => return Err("blank_name"),
+                1 => {}
+                _ => return Err("parent_dir"),
+            }
+        }
+        let stem = trimmed.split('.').next().unwrap_or("");
+        if RESERVED_NAMES.iter().any(|name| name.eq_ignore_ascii_case(stem.trim_end())) {
+            return Err("reserved_name");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_across_folders_ignoring_case() {
+        assert!(glob_match("*readme*.txt", "Addons/ReadMe_First.TXT"));
+        assert!(glob_match("*/thumbs.db", "materials/thumbs.db"));
+        assert!(!glob_match("*/thumbs.db", "thumbs.db"));
+        assert!(glob_match("models/?.mdl", "models/a.mdl"));
+        assert!(!glob_match("models/?.mdl", "models/ab.mdl"));
+        assert!(glob_match("sound/*/coach/*", "sound/player/survivor/voice/coach/hey.wav"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("a*b", "a/c"));
+    }
+
+    #[test]
+    fn accepts_paths_inside_the_folder() {
+        for path in ["a/b.vtf", "./a", "a//b", "a\\b.txt", "a/./b", "a/. /b", "console/x", "a/..b/c", "a/b..c"] {
+            assert_eq!(check_entry_path(path), Ok(()), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_paths_escaping_the_folder() {
+        let cases = [
+            ("", "empty"),
+            ("..", "parent_dir"),
+            ("a/../b", "parent_dir"),
+            (".. /b", "parent_dir"),
+            ("a/.../b", "parent_dir"),
+            ("a\\..\\b", "parent_dir"),
+            ("/etc/passwd", "absolute_path"),
+            ("\\server\\share", "absolute_path"),
+            ("C:/Windows/x.dll", "absolute_path"),
+            ("c:x", "absolute_path"),
+            ("a/ /b", "blank_name"),
+            ("a/b\u{0}c", "invalid_char"),
+            ("a/b\nc", "invalid_char"),
+            ("a/b:c", "invalid_char"),
+            ("a/b*", "invalid_char"),
+            ("a/con", "reserved_name"),
+            ("NUL.txt", "reserved_name"),
+            ("a/com1 .vtf", "reserved_name"),
+            ("lpt9/x", "reserved_name"),
+        ];
+        for (path, reason) in cases {
+            assert_eq!(check_entry_path(path), Err(reason), "{:?}", path);
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::vpk_utils::{archive_path, vpk_set_files};
+
+/// Suffix of files staged next to their target until the transaction is applied.
+const STAGED_SUFFIX: &str = "m4v-new";
+
+/// Suffix of the previous version of each target, kept until the transaction commits.
+const BACKUP_SUFFIX: &str = "m4v-old";
+
+/// One file replaced or removed by a transaction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct InstallOp {
+    /// Final location of the file
+    target: PathBuf,
+    /// New contents waiting next to the target; `None` removes the target
+    staged: Option<PathBuf>,
+    /// Where the previous file is moved; `None` if the target didn't exist
+    backup: Option<PathBuf>,
+}
+
+/// On-disk record of an install in progress.
+///
+/// A journal left in "pending" state means the install was interrupted and is
+/// rolled back on the next start; "done" only means the backups weren't cleaned up.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Journal {
+    state: String,
+    ops: Vec<InstallOp>,
+}
+
+/// Groups file replacements so they are applied all together or not at all.
+///
+/// New contents are staged (written and fsynced) next to each target first. `apply`
+/// records a journal, moves the current files aside and renames the staged ones into
+/// place; `commit` then drops the backups, while `rollback` puts them back.
+pub struct Transaction {
+    journal_path: PathBuf,
+    ops: Vec<InstallOp>,
+    applied: bool,
+}
+
+impl Transaction {
+    pub fn new(journal_path: &Path) -> Self {
+        Self {
+            journal_path: journal_path.to_path_buf(),
+            ops: Vec::new(),
+            applied: false,
+        }
+    }
+
+    /// Stages `source` to replace `target`. The source is moved when it lives on the
+    /// same drive and copied otherwise.
+    pub fn stage_file(&mut self, source: &Path, target: &Path) -> Result<(), String> {
+        let staged = sibling(target, STAGED_SUFFIX);
+        if fs::rename(source, &staged).is_err() {
+            fs::copy(source, &staged)
+                .map_err(|e| format!("Failed to stage {}: {}", target.display(), e))?;
+            let _ = fs::remove_file(source);
+        }
+        sync_file(&staged)?;
+        self.push(target, Some(staged));
+        Ok(())
+    }
+
+    /// Stages new contents for `target`.
+    pub fn stage_bytes(&mut self, target: &Path, data: &[u8]) -> Result<(), String> {
+        let staged = sibling(target, STAGED_SUFFIX);
+        write_synced(&staged, data).map_err(|e| format!("Failed to stage {}: {}", target.display(), e))?;
+        self.push(target, Some(staged));
+        Ok(())
+    }
+
+    /// Marks `target` for removal.
+    pub fn stage_removal(&mut self, target: &Path) {
+        self.push(target, None);
+    }
+
+    fn push(&mut self, target: &Path, staged: Option<PathBuf>) {
+        self.ops.push(InstallOp {
+            target: target.to_path_buf(),
+            staged,
+            backup: target.exists().then(|| sibling(target, BACKUP_SUFFIX)),
+        });
+    }
+
+    /// Puts every staged file in place. On failure, the previous files are restored
+    /// before returning the error.
+    pub fn apply(&mut self) -> Result<(), String> {
+        write_journal(&self.journal_path, "pending", &self.ops)?;
+        self.applied = true;
+
+        if let Err(e) = self.swap_files() {
+            return match self.restore() {
+                Ok(()) => Err(e),
+                Err(restore_error) => Err(format!("{} (rollback failed: {})", e, restore_error)),
+            };
+        }
+        Ok(())
+    }
+
+    fn swap_files(&self) -> Result<(), String> {
+        for op in &self.ops {
+            if let Some(backup) = &op.backup {
+                fs::rename(&op.target, backup)
+                    .map_err(|e| format!("Failed to back up {}: {}", op.target.display(), e))?;
+            }
+            if let Some(staged) = &op.staged {
+                fs::rename(staged, &op.target)
+                    .map_err(|e| format!("Failed to install {}: {}", op.target.display(), e))?;
+            }
+        }
+        sync_parent_dirs(&self.ops);
+        Ok(())
+    }
+
+    /// Makes the applied files permanent and removes the backups.
+    pub fn commit(self) -> Result<(), String> {
+        if self.applied {
+            write_journal(&self.journal_path, "done", &self.ops)?;
+        }
+        finish(&self.journal_path, &self.ops);
+        Ok(())
+    }
+
+    /// Restores the files as they were before `apply` and discards the staged ones.
+    pub fn rollback(self) -> Result<(), String> {
+        if self.applied {
+            self.restore()
+        } else {
+            finish(&self.journal_path, &self.ops);
+            Ok(())
+        }
+    }
+
+    fn restore(&self) -> Result<(), String> {
+        undo(&self.ops)?;
+        finish(&self.journal_path, &self.ops);
+        Ok(())
+    }
+}
+
+/// Returns true for staged files and backups created by a transaction.
+pub fn is_staging_file(file_name: &str) -> bool {
+    file_name.ends_with(&format!(".{}", STAGED_SUFFIX)) || file_name.ends_with(&format!(".{}", BACKUP_SUFFIX))
+}
+
+/// Replaces the VPK set at `dest_dir_vpk` (directory VPK plus numbered archives)
+/// with the one at `src_dir_vpk` in a single transaction. Extra archives of the
+/// previous set are removed as part of it.
+pub fn install_vpk_set(src_dir_vpk: &Path, dest_dir_vpk: &Path, journal_path: &Path) -> Result<(), String> {
+    let sources = vpk_set_files(src_dir_vpk);
+    if sources.is_empty() {
+        return Err(format!("{} not found", src_dir_vpk.display()));
+    }
+
+    let mut transaction = Transaction::new(journal_path);
+    let staged = (|| {
+        for (position, source) in sources.iter().enumerate() {
+            let target = if position == 0 {
+                dest_dir_vpk.to_path_buf()
+            } else {
+                archive_path(dest_dir_vpk, (position - 1) as u16)
+            };
+            transaction.stage_file(source, &target)?;
+        }
+        for stale in vpk_set_files(dest_dir_vpk).into_iter().skip(sources.len()) {
+            transaction.stage_removal(&stale);
+        }
+        Ok::<_, String>(())
+    })();
+    if let Err(e) = staged {
+        let _ = transaction.rollback();
+        return Err(e);
+    }
+
+    transaction.apply()?;
+    transaction.commit()
+}
+
+/// Replaces a single file atomically, e.g. `gameinfo.txt`.
+pub fn install_file(target: &Path, data: &[u8], journal_path: &Path) -> Result<(), String> {
+    let mut transaction = Transaction::new(journal_path);
+    if let Err(e) = transaction.stage_bytes(target, data) {
+        let _ = transaction.rollback();
+        return Err(e);
+    }
+    transaction.apply()?;
+    transaction.commit()
+}
+
+/// Finishes or undoes an install interrupted by a crash or power loss.
+///
+/// Returns `Ok(true)` when a pending install was rolled back.
+pub fn recover_interrupted_install(journal_path: &Path) -> Result<bool, String> {
+    let content = match fs::read_to_string(journal_path) {
+        Ok(content) => content,
+        Err(_) => return Ok(false),
+    };
+    let journal: Journal = match serde_json::from_str(&content) {
+        Ok(journal) => journal,
+        Err(_) => {
+            // A torn journal was never acted on: it is written before any file is moved
+            let _ = fs::remove_file(journal_path);
+            return Ok(false);
+        }
+    };
+
+    let rolled_back = journal.state != "done";
+    if rolled_back {
+        undo(&journal.ops)?;
+    }
+    finish(journal_path, &journal.ops);
+    Ok(rolled_back)
+}
+
+/// Moves every backup back over its target. Safe to run at any point of `apply`.
+fn undo(ops: &[InstallOp]) -> Result<(), String> {
+    for op in ops.iter().rev() {
+        match &op.backup {
+            Some(backup) if backup.exists() => {
+                if op.target.exists() {
+                    fs::remove_file(&op.target)
+                        .map_err(|e| format!("Failed to remove {}: {}", op.target.display(), e))?;
+                }
+                fs::rename(backup, &op.target)
+                    .map_err(|e| format!("Failed to restore {}: {}", op.target.display(), e))?;
+            }
+            // The target was never moved aside, so it still holds the previous file
+            Some(_) => {}
+            // The target didn't exist before; remove it if the staged file got there
+            None => {
+                let installed = op.staged.as_ref().map(|staged| !staged.exists()).unwrap_or(false);
+                if installed && op.target.exists() {
+                    fs::remove_file(&op.target)
+                        .map_err(|e| format!("Failed to remove {}: {}", op.target.display(), e))?;
+                }
+            }
+        }
+    }
+    sync_parent_dirs(ops);
+    Ok(())
+}
+
+/// Deletes leftover staged files, backups and the journal.
+fn finish(journal_path: &Path, ops: &[InstallOp]) {
+    for op in ops {
+        if let Some(staged) = &op.staged {
+            let _ = fs::remove_file(staged);
+        }
+        if let Some(backup) = &op.backup {
+            let _ = fs::remove_file(backup);
+        }
+    }
+    let _ = fs::remove_file(journal_path);
+    sync_dir(journal_path);
+}
+
+fn write_journal(journal_path: &Path, state: &str, ops: &[InstallOp]) -> Result<(), String> {
+    let journal = Journal {
+        state: state.to_string(),
+        ops: ops.to_vec(),
+    };
+    let data = serde_json::to_vec_pretty(&journal).map_err(|e| e.to_string())?;
+    if let Some(parent) = journal_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    write_atomic(journal_path, &data)
+}
+
+/// Replaces `path` with `data` so a crash leaves either the old or the new contents,
+/// never a torn file: the data is written and flushed aside, then renamed over it.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), String> {
+    let temp = sibling(path, "tmp");
+    write_synced(&temp, data).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    if let Err(e) = fs::rename(&temp, path) {
+        let _ = fs::remove_file(&temp);
+        return Err(format!("Failed to write {}: {}", path.display(), e));
+    }
+    sync_dir(path);
+    Ok(())
+}
+
+/// `file.ext` becomes `file.ext.<suffix>` in the same folder, so renames stay on one drive.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+fn sync_file(path: &Path) -> Result<(), String> {
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.sync_all())
+        .map_err(|e| format!("Failed to flush {}: {}", path.display(), e))
+}
+
+fn sync_parent_dirs(ops: &[InstallOp]) {
+    for op in ops {
+        sync_dir(&op.target);
+    }
+}
+
+/// Flushes the directory entry of `path` so renames survive a power loss.
+/// Windows has no equivalent for directories; renames there are journaled by NTFS.
+pub fn sync_dir(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn commit_replaces_files_and_cleans_up() {
+        let dir = TestDir::new("install-commit");
+        let journal = dir.0.join("journal.json");
+        let target = dir.file("a.vpk", "old");
+        let source = dir.file("source", "new");
+
+        let mut transaction = Transaction::new(&journal);
+        transaction.stage_file(&source, &target).unwrap();
+        transaction.apply().unwrap();
+        transaction.commit().unwrap();
+
+        assert_eq!(read(&target), "new");
+        assert_eq!(dir.listing(), vec!["a.vpk"]);
+    }
+
+    #[test]
+    fn rollback_restores_removed_and_new_targets() {
+        let dir = TestDir::new("install-rollback");
+        let journal = dir.0.join("journal.json");
+        let removed = dir.file("removed.vpk", "keep me");
+        let created = dir.0.join("created.vpk");
+
+        let mut transaction = Transaction::new(&journal);
+        transaction.stage_removal(&removed);
+        transaction.stage_bytes(&created, b"new file").unwrap();
+        transaction.apply().unwrap();
+        assert!(!removed.exists());
+        assert_eq!(read(&created), "new file");
+
+        transaction.rollback().unwrap();
+        assert_eq!(read(&removed), "keep me");
+        assert!(!created.exists());
+        assert_eq!(dir.listing(), vec!["removed.vpk"]);
+    }
+
+    #[test]
+    fn committed_removal_deletes_the_target() {
+        let dir = TestDir::new("install-removal");
+        let journal = dir.0.join("journal.json");
+        let removed = dir.file("removed.vpk", "old");
+
+        let mut transaction = Transaction::new(&journal);
+        transaction.stage_removal(&removed);
+        transaction.apply().unwrap();
+        transaction.commit().unwrap();
+
+        assert!(dir.listing().is_empty());
+    }
+
+    #[test]
+    fn recovery_undoes_a_partly_applied_install() {
+        let dir = TestDir::new("install-partial");
+        let journal = dir.0.join("journal.json");
+        let swapped = dir.file("swapped.vpk", "old swapped");
+        let untouched = dir.file("untouched.vpk", "old untouched");
+        let removed = dir.file("removed.vpk", "old removed");
+        let created = dir.0.join("created.vpk");
+
+        let mut transaction = Transaction::new(&journal);
+        transaction.stage_bytes(&swapped, b"new swapped").unwrap();
+        transaction.stage_bytes(&created, b"new created").unwrap();
+        transaction.stage_removal(&removed);
+        transaction.stage_bytes(&untouched, b"new untouched").unwrap();
+        write_journal(&journal, "pending", &transaction.ops).unwrap();
+
+        // Crash after the first three ops: the last target was never backed up or replaced
+        for op in &transaction.ops[..3] {
+            if let Some(backup) = &op.backup {
+                fs::rename(&op.target, backup).unwrap();
+            }
+            if let Some(staged) = &op.staged {
+                fs::rename(staged, &op.target).unwrap();
+            }
+        }
+        drop(transaction);
+
+        assert!(recover_interrupted_install(&journal).unwrap());
+        assert_eq!(read(&swapped), "old swapped");
+        assert_eq!(read(&untouched), "old untouched");
+        assert_eq!(read(&removed), "old removed");
+        assert!(!created.exists());
+        assert_eq!(dir.listing(), vec!["removed.vpk", "swapped.vpk", "untouched.vpk"]);
+    }
+
+    #[test]
+    fn recovery_keeps_a_finished_install() {
+        let dir = TestDir::new("install-done");
+        let journal = dir.0.join("journal.json");
+        let target = dir.file("a.vpk", "old");
+
+        let mut transaction = Transaction::new(&journal);
+        transaction.stage_bytes(&target, b"new").unwrap();
+        transaction.apply().unwrap();
+        // Crash after marking the journal done, before the backups were removed
+        write_journal(&journal, "done", &transaction.ops).unwrap();
+        drop(transaction);
+
+        assert!(!recover_interrupted_install(&journal).unwrap());
+        assert_eq!(read(&target), "new");
+        assert_eq!(dir.listing(), vec!["a.vpk"]);
+    }
+
+    #[test]
+    fn recovery_ignores_a_torn_journal() {
+        let dir = TestDir::new("install-torn");
+        let target = dir.file("a.vpk", "current");
+        let journal = dir.file("journal.json", "{\"state\": \"pend");
+
+        assert!(!recover_interrupted_install(&journal).unwrap());
+        assert_eq!(read(&target), "current");
+        assert_eq!(dir.listing(), vec!["a.vpk"]);
+
+        // No journal at all is not an error either
+        assert!(!recover_interrupted_install(&journal).unwrap());
+    }
+}
@@ -5,11 +5,24 @@
 )]
 
 mod commands;
+mod filters;
+mod install;
+mod mod_cache;
 mod mod_types;
 mod paths;
+mod settings;
+#[cfg(test)]
+mod test_utils;
+mod vpk_reader;
 mod vpk_utils;
+mod vtf;
 
-use commands::{delete_mods, get_mods, merge_mods, verify_and_repair_environment};
+use commands::{
+    clean_merge_leftovers, delete_mods, extract_mod, get_cache_size, get_load_order, get_mods,
+    get_winner_rules, merge_mods, move_mod, plan_merge, prune_cache, set_winner_rules,
+    verify_and_repair_environment, verify_vpk,
+};
+use tauri::Manager;
 
 fn main() {
     // Run self-healing on startup (silently handle errors)
@@ -22,11 +35,27 @@ fn main() {
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_updater::Builder::new().build())
         .plugin(tauri_plugin_process::init())
+        .setup(|app| {
+            paths::init_cache_dir(app.path().app_cache_dir()?);
+            paths::init_config_dir(app.path().app_config_dir()?);
+            // Remove scratch files left behind by merges that crashed
+            clean_merge_leftovers();
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             verify_and_repair_environment,
             get_mods,
             merge_mods,
+            plan_merge,
+            get_winner_rules,
+            set_winner_rules,
+            get_load_order,
+            move_mod,
             delete_mods,
+            verify_vpk,
+            extract_mod,
+            get_cache_size,
+            prune_cache,
             get_donation_qr,
         ])
         .run(tauri::generate_context!())
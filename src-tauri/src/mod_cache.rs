@@ -0,0 +1,438 @@
+//! Persistent cache of parsed Workshop VPKs
+//!
+//! Each source VPK gets an index file named after the hash of its path, size and
+//! modification time, holding the file table resolved to data locations. When
+//! blobs are enabled, every file is also stored once under the MD5 of its
+//! contents, which lets merges deduplicate without hashing anything again.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+
+use crate::install;
+use crate::mod_types::CacheReport;
+use crate::vpk_reader::entry_path;
+use crate::vpk_utils::{self, EntrySource, PackEntry};
+
+/// Bump when the index format changes so older indexes are rebuilt.
+const INDEX_VERSION: u32 = 1;
+
+/// File table of one source VPK, as stored on disk.
+#[derive(Serialize, Deserialize)]
+struct CachedIndex {
+    version: u32,
+    source: PathBuf,
+    size: u64,
+    modified: u128,
+    /// True when every non-empty file has a blob
+    blobs: bool,
+    entries: Vec<CachedEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedEntry {
+    ext: String,
+    dir: String,
+    name: String,
+    size: u64,
+    crc: u32,
+    /// File holding the data after the preload bytes, and where it starts
+    archive: PathBuf,
+    offset: u64,
+    preload: Vec<u8>,
+    /// Hex MD5 of the contents; names the blob when blobs are stored
+    md5: Option<String>,
+}
+
+/// Cache of parsed VPK file tables and, optionally, content-addressed file blobs.
+pub struct ModCache {
+    root: PathBuf,
+}
+
+impl ModCache {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn index_dir(&self) -> PathBuf {
+        self.root.join("index")
+    }
+
+    fn blob_dir(&self) -> PathBuf {
+        self.root.join("blobs")
+    }
+
+    fn blob_path(&self, md5: &str) -> PathBuf {
+        self.blob_dir().join(&md5[..2]).join(md5)
+    }
+
+    /// Returns the entries of `vpk_path`, reusing the cached index while the file is
+    /// unchanged. With `store_blobs`, file contents are copied into the cache too.
+    ///
+    /// Cache write failures are logged and ignored; the entries are still returned.
+    pub fn index_vpk(&self, vpk_path: &Path, store_blobs: bool) -> Result<Vec<PackEntry>, String> {
+        let (key, size, modified) = source_key(vpk_path)?;
+        if let Some(cached) = self.load_index(&key) {
+            if cached.size == size && cached.modified == modified && (cached.blobs || !store_blobs) {
+                return Ok(self.to_pack_entries(&cached));
+            }
+        }
+
+        let entries = vpk_utils::index_vpk(vpk_path)?;
+        let mut cached = CachedIndex {
+            version: INDEX_VERSION,
+            source: vpk_path.to_path_buf(),
+            size,
+            modified,
+            blobs: false,
+            entries: entries.iter().filter_map(to_cached_entry).collect(),
+        };
+
+        if store_blobs {
+            let md5s = vpk_utils::parallel_map(&entries, || (), |_, entry| self.store_blob(entry));
+            match md5s.into_iter().collect::<Result<Vec<_>, String>>() {
+                Ok(md5s) => {
+                    for (cached_entry, md5) in cached.entries.iter_mut().zip(md5s) {
+                        cached_entry.md5 = md5;
+                    }
+                    cached.blobs = true;
+                }
+                Err(e) => eprintln!("Error guardando archivos en caché: {}", e),
+            }
+        }
+
+        if let Err(e) = self.save_index(&key, &cached) {
+            eprintln!("Error guardando índice en caché: {}", e);
+        }
+        Ok(self.to_pack_entries(&cached))
+    }
+
+    /// Reads one file of a cached VPK (case-insensitive path). Returns `None` when the
+    /// VPK isn't cached or changed since it was indexed, or the file is over `max_size` bytes.
+    pub fn read_file(&self, vpk_path: &Path, path: &str, max_size: u64) -> Option<Vec<u8>> {
+        let (key, size, modified) = source_key(vpk_path).ok()?;
+        let cached = self.load_index(&key).filter(|cached| cached.size == size && cached.modified == modified)?;
+        let entry = cached
+            .entries
+            .iter()
+            .find(|entry| entry_path(&entry.dir, &entry.name, &entry.ext).eq_ignore_ascii_case(path))
+            .filter(|entry| entry.size <= max_size)?;
+
+        let mut data = Vec::with_capacity(entry.size as usize);
+        vpk_utils::copy_entry(&self.to_pack_entry(entry), &mut data).ok()?;
+        Some(data)
+    }
+
+    /// Counts the cached indexes and blobs and the bytes they use.
+    pub fn report(&self) -> CacheReport {
+        let mut report = CacheReport::default();
+        for path in files_under(&self.index_dir()) {
+            report.index_files += 1;
+            report.total_bytes += fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        }
+        for path in files_under(&self.blob_dir()) {
+            report.blob_files += 1;
+            report.total_bytes += fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        }
+        report
+    }
+
+    /// Removes indexes whose source VPK was deleted or changed, then every blob no
+    /// remaining index references. With `all`, the whole cache is deleted.
+    pub fn prune(&self, all: bool) -> Result<CacheReport, String> {
+        if all {
+            if self.root.exists() {
+                fs::remove_dir_all(&self.root).map_err(|e| format!("Failed to clear cache: {}", e))?;
+            }
+            return Ok(self.report());
+        }
+
+        let mut referenced: HashSet<String> = HashSet::new();
+        for path in files_under(&self.index_dir()) {
+            let cached = fs::read(&path)
+                .ok()
+                .and_then(|data| serde_json::from_slice::<CachedIndex>(&data).ok())
+                .filter(|cached| cached.version == INDEX_VERSION);
+            let current = cached.filter(|cached| {
+                source_key(&cached.source)
+                    .map(|(key, size, modified)| {
+                        path.file_stem().map(|stem| stem == key.as_str()).unwrap_or(false)
+                            && cached.size == size
+                            && cached.modified == modified
+                    })
+                    .unwrap_or(false)
+            });
+            match current {
+                Some(cached) => referenced.extend(cached.entries.into_iter().filter_map(|entry| entry.md5)),
+                None => {
+                    fs::remove_file(&path).map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+                }
+            }
+        }
+
+        for path in files_under(&self.blob_dir()) {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            if !referenced.contains(&name) {
+                fs::remove_file(&path).map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+            }
+        }
+        Ok(self.report())
+    }
+
+    fn load_index(&self, key: &str) -> Option<CachedIndex> {
+        let data = fs::read(self.index_dir().join(format!("{}.json", key))).ok()?;
+        serde_json::from_slice::<CachedIndex>(&data)
+            .ok()
+            .filter(|cached| cached.version == INDEX_VERSION)
+    }
+
+    fn save_index(&self, key: &str, cached: &CachedIndex) -> Result<(), String> {
+        let index_dir = self.index_dir();
+        fs::create_dir_all(&index_dir).map_err(|e| e.to_string())?;
+        let data = serde_json::to_vec(cached).map_err(|e| e.to_string())?;
+        install::write_atomic(&index_dir.join(format!("{}.json", key)), &data)
+    }
+
+    /// Copies the contents of `entry` into the blob store and returns their hex MD5.
+    fn store_blob(&self, entry: &PackEntry) -> Result<Option<String>, String> {
+        if entry.size == 0 {
+            return Ok(None);
+        }
+        let blob_dir = self.blob_dir();
+        fs::create_dir_all(&blob_dir).map_err(|e| e.to_string())?;
+
+        let temp = blob_dir.join(format!("{}.tmp", unique_suffix()));
+        let mut out = HashingWriter {
+            file: File::create(&temp).map_err(|e| format!("Failed to write {}: {}", temp.display(), e))?,
+            md5: Md5::new(),
+        };
+        // Flushed before the rename so a power loss can't leave a truncated blob behind
+        let copied = vpk_utils::copy_entry(entry, &mut out)
+            .and_then(|_| out.file.sync_all().map_err(|e| format!("Failed to write {}: {}", temp.display(), e)));
+        if let Err(e) = copied {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        let md5 = format!("{:x}", out.md5.finalize());
+
+        let blob = self.blob_path(&md5);
+        if blob.exists() {
+            let _ = fs::remove_file(&temp);
+            return Ok(Some(md5));
+        }
+        let moved = blob
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::rename(&temp, &blob));
+        if let Err(e) = moved {
+            let _ = fs::remove_file(&temp);
+            return Err(format!("Failed to write {}: {}", blob.display(), e));
+        }
+        install::sync_dir(&blob);
+        Ok(Some(md5))
+    }
+
+    fn to_pack_entries(&self, cached: &CachedIndex) -> Vec<PackEntry> {
+        cached.entries.iter().map(|entry| self.to_pack_entry(entry)).collect()
+    }
+
+    /// Blobs are preferred over the source VPK when present; both hold the same bytes.
+    fn to_pack_entry(&self, entry: &CachedEntry) -> PackEntry {
+        let blob = entry.md5.as_deref().map(|md5| self.blob_path(md5)).filter(|blob| blob.exists());
+        let md5 = entry.md5.as_deref().and_then(parse_md5);
+        PackEntry {
+            ext: entry.ext.clone(),
+            dir: entry.dir.clone(),
+            name: entry.name.clone(),
+            source: match blob {
+                Some(blob) => EntrySource::Blob(blob),
+                None => EntrySource::Vpk {
+                    archive: entry.archive.clone(),
+                    offset: entry.offset,
+                    preload: entry.preload.clone(),
+                },
+            },
+            size: entry.size,
+            crc: Some(entry.crc),
+            md5,
+        }
+    }
+}
+
+fn to_cached_entry(entry: &PackEntry) -> Option<CachedEntry> {
+    let EntrySource::Vpk { archive, offset, preload } = &entry.source else {
+        return None;
+    };
+    Some(CachedEntry {
+        ext: entry.ext.clone(),
+        dir: entry.dir.clone(),
+        name: entry.name.clone(),
+        size: entry.size,
+        crc: entry.crc.unwrap_or_default(),
+        archive: archive.clone(),
+        offset: *offset,
+        preload: preload.clone(),
+        md5: None,
+    })
+}
+
+/// Hash naming the index of a source VPK, plus the size and modification time it covers.
+fn source_key(vpk_path: &Path) -> Result<(String, u64, u128), String> {
+    let metadata = fs::metadata(vpk_path).map_err(|e| format!("Failed to read {}: {}", vpk_path.display(), e))?;
+    let modified = vpk_utils::modified_nanos(&metadata);
+    let description = format!("{}|{}|{}", vpk_path.display(), metadata.len(), modified);
+    Ok((format!("{:x}", Md5::digest(description.as_bytes())), metadata.len(), modified))
+}
+
+fn parse_md5(hex: &str) -> Option<[u8; 16]> {
+    let mut md5 = [0u8; 16];
+    if hex.len() != 32 {
+        return None;
+    }
+    for (index, byte) in md5.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    Some(md5)
+}
+
+/// Name for a temporary file that can't collide with other workers.
+fn unique_suffix() -> String {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!("{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Lists every file below `dir`, recursively.
+fn files_under(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&current) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files
+}
+
+/// Writes to a file while computing the MD5 of everything written.
+struct HashingWriter {
+    file: File,
+    md5: Md5,
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.md5.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+    use std::time::{Duration, SystemTime};
+
+    fn write_vpk(path: &Path, files: &[(&str, &[u8])]) {
+        let entries = files
+            .iter()
+            .map(|(file_path, data)| PackEntry::from_memory(file_path, data.to_vec()))
+            .collect();
+        vpk_utils::pack_vpk(entries, path, &vpk_utils::PackOptions::default()).unwrap();
+    }
+
+    fn blob_names(cache: &ModCache) -> Vec<String> {
+        let mut names: Vec<_> = files_under(&cache.blob_dir())
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn rebuilds_the_index_when_the_source_changes() {
+        let dir = TestDir::new("cache-invalidate");
+        let cache = ModCache::new(&dir.0.join("cache"));
+        let vpk = dir.0.join("mod.vpk");
+        write_vpk(&vpk, &[("addoninfo.txt", b"first")]);
+        cache.index_vpk(&vpk, false).unwrap();
+        assert_eq!(cache.read_file(&vpk, "ADDONINFO.TXT", 64).as_deref(), Some(&b"first"[..]));
+        assert_eq!(cache.read_file(&vpk, "addoninfo.txt", 4), None);
+
+        // Same size, newer modification time
+        let later = SystemTime::now() + Duration::from_secs(60);
+        File::options().write(true).open(&vpk).unwrap().set_modified(later).unwrap();
+        assert_eq!(cache.read_file(&vpk, "addoninfo.txt", 64), None);
+        cache.index_vpk(&vpk, false).unwrap();
+        assert!(cache.read_file(&vpk, "addoninfo.txt", 64).is_some());
+
+        // Different size
+        write_vpk(&vpk, &[("addoninfo.txt", b"second version")]);
+        assert_eq!(cache.read_file(&vpk, "addoninfo.txt", 64), None);
+        let entries = cache.index_vpk(&vpk, false).unwrap();
+        assert_eq!(entries[0].size, 14);
+        assert_eq!(cache.read_file(&vpk, "addoninfo.txt", 64).as_deref(), Some(&b"second version"[..]));
+    }
+
+    #[test]
+    fn index_without_blobs_when_storing_fails() {
+        let dir = TestDir::new("cache-blob-failure");
+        let cache = ModCache::new(&dir.0.join("cache"));
+        let vpk = dir.0.join("mod.vpk");
+        write_vpk(&vpk, &[("models/a.mdl", b"model")]);
+        // A file where the blob folder should be makes every blob write fail
+        dir.file("cache/blobs", "");
+
+        let entries = cache.index_vpk(&vpk, true).unwrap();
+        assert!(matches!(entries[0].source, EntrySource::Vpk { .. }));
+        let (key, _, _) = source_key(&vpk).unwrap();
+        let cached = cache.load_index(&key).unwrap();
+        assert!(!cached.blobs);
+        assert!(cached.entries.iter().all(|entry| entry.md5.is_none()));
+    }
+
+    #[test]
+    fn prune_keeps_referenced_blobs_only() {
+        let dir = TestDir::new("cache-prune");
+        let cache = ModCache::new(&dir.0.join("cache"));
+        let kept = dir.0.join("kept.vpk");
+        let removed = dir.0.join("removed.vpk");
+        write_vpk(&kept, &[("models/shared.mdl", b"shared"), ("models/kept.mdl", b"kept")]);
+        write_vpk(&removed, &[("models/shared.mdl", b"shared"), ("models/removed.mdl", b"removed")]);
+        cache.index_vpk(&kept, true).unwrap();
+        cache.index_vpk(&removed, true).unwrap();
+        dir.file("cache/blobs/00/00000000000000000000000000000000", "orphan");
+        assert_eq!(cache.report().blob_files, 4);
+
+        fs::remove_file(&removed).unwrap();
+        let report = cache.prune(false).unwrap();
+
+        let mut expected: Vec<String> =
+            [&b"kept"[..], b"shared"].iter().map(|data| format!("{:x}", Md5::digest(data))).collect();
+        expected.sort();
+        assert_eq!(blob_names(&cache), expected);
+        assert_eq!((report.index_files, report.blob_files), (1, 2));
+        let entries = cache.index_vpk(&kept, true).unwrap();
+        assert!(entries.iter().all(|entry| matches!(entry.source, EntrySource::Blob(_))));
+    }
+}
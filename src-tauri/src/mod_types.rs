@@ -1,6 +1,9 @@
 //! Data structures for mod management
 use serde::{Deserialize, Serialize};
 
+use crate::filters::{DEFAULT_STRIP_EXTENSIONS, DEFAULT_STRIP_PATTERNS};
+use crate::vpk_utils::DEFAULT_ARCHIVE_SIZE;
+
 /// Represents a single mod from the Workshop
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Mod {
@@ -12,6 +15,59 @@ pub struct Mod {
     pub title: String,
 }
 
+/// Options accepted by the merge command.
+/// Every field has a default so the frontend can omit the whole object.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MergeOptions {
+    /// VPK version of the generated pack: 1 for L4D2, 2 for other Source branches
+    pub vpk_version: u32,
+    /// Maximum bytes per numbered archive (`pak01_NNN.vpk`); 0 uses the default 200 MiB
+    pub archive_size: u64,
+    /// Files up to this many bytes are inlined as preload data (0 disables it)
+    pub preload_threshold: u64,
+    /// Files with these extensions (no dot, any case) are left out of the pack
+    pub strip_extensions: Vec<String>,
+    /// Files whose path matches one of these globs (`*`, `?`, any case) are left out
+    pub strip_patterns: Vec<String>,
+    /// "Lite" mode: drop the largest mip levels of VTF textures to shrink the pack
+    pub lite_textures: bool,
+    /// Mip levels removed from each texture in lite mode
+    pub lite_drop_mips: u32,
+    /// Lite mode never shrinks the largest side of a texture below this many pixels
+    pub lite_min_size: u32,
+    /// Refuse to merge if any single file is larger than this many bytes (0 = no limit)
+    pub max_entry_size: u64,
+    /// Refuse to merge if the pack would be larger than this many bytes (0 = no limit)
+    pub max_total_size: u64,
+    /// Update the installed pack in place instead of rebuilding it (VPK v1 only)
+    pub incremental: bool,
+    /// Rebuild from scratch once this share of archive bytes is no longer referenced
+    pub max_fragmentation: f64,
+    /// Also copy file contents into the mod cache, so later merges skip re-hashing
+    pub cache_blobs: bool,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        Self {
+            vpk_version: 1,
+            archive_size: DEFAULT_ARCHIVE_SIZE,
+            preload_threshold: 0,
+            strip_extensions: DEFAULT_STRIP_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+            strip_patterns: DEFAULT_STRIP_PATTERNS.iter().map(|pattern| pattern.to_string()).collect(),
+            lite_textures: false,
+            lite_drop_mips: 1,
+            lite_min_size: 256,
+            max_entry_size: 1024 * 1024 * 1024,
+            max_total_size: 32 * 1024 * 1024 * 1024,
+            incremental: false,
+            max_fragmentation: 0.3,
+            cache_blobs: false,
+        }
+    }
+}
+
 /// Result of a merge operation
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MergeResult {
@@ -19,6 +75,112 @@ pub struct MergeResult {
     pub status: String,
     /// Human-readable message
     pub msg: String,
+    /// Bytes not written because identical files were stored only once
+    pub bytes_saved: u64,
+    /// True when the installed pack already matched the request and nothing was rebuilt
+    pub up_to_date: bool,
+    /// Bytes saved by lite mode, per mod whose textures were shrunk
+    pub lite_savings: Vec<LiteSavings>,
+    /// Files left out of the pack by the strip list
+    pub stripped_files: Vec<StrippedFile>,
+    /// Files left out because their path would escape the output folder
+    pub rejected_files: Vec<RejectedFile>,
+    /// Problems that didn't stop the merge (e.g. paths differing only in case)
+    pub warnings: Vec<String>,
+    /// Paths shipped by more than one mod, with the mod whose file was kept
+    pub conflicts: Vec<MergeConflict>,
+    /// Conflicts won and lost by each mod involved in at least one
+    pub conflict_counts: Vec<ModConflictCount>,
+}
+
+/// A selected mod that contributed nothing to the merge
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SkippedMod {
+    pub mod_id: String,
+    /// "missing" when the VPK isn't in the Workshop folder, otherwise the read error
+    pub reason: String,
+}
+
+/// One file of the pack a merge would produce
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlannedFile {
+    /// Normalized path inside the pack
+    pub path: String,
+    /// Mod the file is taken from
+    pub mod_id: String,
+    pub size: u64,
+}
+
+/// What `merge_mods` would do for a selection, computed without writing anything
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MergePlan {
+    /// Files of the resulting pack, sorted by path
+    pub files: Vec<PlannedFile>,
+    pub conflicts: Vec<MergeConflict>,
+    pub conflict_counts: Vec<ModConflictCount>,
+    pub skipped_mods: Vec<SkippedMod>,
+    pub stripped_files: Vec<StrippedFile>,
+    pub rejected_files: Vec<RejectedFile>,
+    /// Upper bound of the pack size in bytes: no deduplication or lite mode applied
+    pub estimated_size: u64,
+    /// Merge warnings, plus every reason the merge would be refused (size limits, free space)
+    pub warnings: Vec<String>,
+}
+
+/// A path provided by several selected mods; only the winner's file is packed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MergeConflict {
+    pub path: String,
+    /// Mod whose file ends up in the pack: the one a winner rule pins, otherwise
+    /// the one highest in the load order
+    pub winner: String,
+    /// Mods whose file was overridden, lowest priority first
+    pub losers: Vec<String>,
+    /// True when a winner rule chose the winner
+    pub pinned: bool,
+}
+
+/// Pins which mod provides the files matching `pattern` when several mods ship them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WinnerRule {
+    /// Path inside the pack or glob (`*` and `?`), e.g. `sound/player/survivor/voice/coach/*`
+    pub pattern: String,
+    pub mod_id: String,
+}
+
+/// How many conflicting paths a mod won and lost
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModConflictCount {
+    pub mod_id: String,
+    pub won: usize,
+    pub lost: usize,
+}
+
+/// A file whose path was refused (absolute, `..`, reserved names, ...)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RejectedFile {
+    pub mod_id: String,
+    /// Path exactly as stored in the source VPK
+    pub path: String,
+    /// "absolute_path", "parent_dir", "blank_name", "invalid_char", "reserved_name" or "empty"
+    pub reason: String,
+}
+
+/// A file left out of the merged pack because it matched the strip list
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StrippedFile {
+    pub mod_id: String,
+    pub path: String,
+    pub size: u64,
+}
+
+/// Bytes saved by lite mode on the textures of one mod
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LiteSavings {
+    pub mod_id: String,
+    /// Number of textures rewritten with fewer mip levels
+    pub textures: usize,
+    pub bytes_saved: u64,
 }
 
 impl MergeResult {
@@ -26,6 +188,14 @@ impl MergeResult {
         Self {
             status: "ok".to_string(),
             msg: msg.into(),
+            bytes_saved: 0,
+            up_to_date: false,
+            lite_savings: Vec::new(),
+            stripped_files: Vec::new(),
+            rejected_files: Vec::new(),
+            warnings: Vec::new(),
+            conflicts: Vec::new(),
+            conflict_counts: Vec::new(),
         }
     }
 
@@ -33,6 +203,79 @@ impl MergeResult {
         Self {
             status: "error".to_string(),
             msg: msg.into(),
+            bytes_saved: 0,
+            up_to_date: false,
+            lite_savings: Vec::new(),
+            stripped_files: Vec::new(),
+            rejected_files: Vec::new(),
+            warnings: Vec::new(),
+            conflicts: Vec::new(),
+            conflict_counts: Vec::new(),
         }
     }
 }
+
+/// Integrity check result for a single file inside a VPK
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileCheck {
+    /// Path inside the VPK
+    pub path: String,
+    /// Size in bytes (preload included)
+    pub size: u64,
+    /// "ok", "crc_mismatch", "out_of_bounds", "missing_archive" or "read_error"
+    pub status: String,
+}
+
+/// Result of verifying a VPK
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// Path of the verified directory VPK
+    pub path: String,
+    /// True when no structural error and no bad file was found
+    pub ok: bool,
+    /// VPK version from the header (0 if the header couldn't be read)
+    pub version: u32,
+    /// Number of files in the tree
+    pub file_count: usize,
+    /// Number of files whose check failed
+    pub bad_files: usize,
+    /// Structural problems (header, tree, v2 checksums, signature)
+    pub errors: Vec<String>,
+    /// Per-file results
+    pub files: Vec<FileCheck>,
+}
+
+/// Progress of an extraction, emitted as the "extract-progress" event
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExtractProgress {
+    /// Files processed so far
+    pub done: usize,
+    /// Files that will be processed in total
+    pub total: usize,
+}
+
+/// Summary of an extraction
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ExtractSummary {
+    /// Folder the files were written to
+    pub out_dir: String,
+    /// Number of files written
+    pub files_written: usize,
+    /// Total bytes written
+    pub bytes_written: u64,
+    /// Root-level files (like addoninfo.txt) left out
+    pub skipped_root_files: usize,
+    /// Paths refused because they would be written outside `out_dir`
+    pub rejected_paths: Vec<String>,
+}
+
+/// Size of the persistent mod cache
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CacheReport {
+    /// Cached file tables, one per indexed VPK version
+    pub index_files: usize,
+    /// Content-addressed file blobs
+    pub blob_files: usize,
+    /// Total bytes used on disk
+    pub total_bytes: u64,
+}
@@ -5,6 +5,12 @@ use std::sync::OnceLock;
 /// Cache for the installation directory path to avoid repeated registry lookups
 static INSTALL_DIR: OnceLock<PathBuf> = OnceLock::new();
 
+/// App-owned cache directory, provided by Tauri on startup
+static CACHE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// App-owned config directory, provided by Tauri on startup
+static CONFIG_DIR: OnceLock<PathBuf> = OnceLock::new();
+
 /// Path to L4D2 installation root (e.g., .../common/Left 4 Dead 2)
 pub fn get_install_dir() -> &'static PathBuf {
     INSTALL_DIR.get_or_init(|| {
@@ -35,10 +41,55 @@ pub fn get_mods_path() -> PathBuf {
     get_install_dir().join("mods")
 }
 
+/// Journal of the install in progress, used to roll back interrupted installs on startup
+pub fn get_install_journal_path() -> PathBuf {
+    get_mods_path().join("mods4versus_install.json")
+}
+
 /// Path to gameinfo.txt configuration file
 pub fn get_gameinfo_path() -> PathBuf {
     get_game_dir().join("gameinfo.txt")
 }
 
-/// Temporary directory name for VPK merging
+/// Sets the app cache directory (Tauri's `app_cache_dir`). Only the first call has effect.
+pub fn init_cache_dir(dir: PathBuf) {
+    let _ = CACHE_DIR.set(dir);
+}
+
+/// App-owned cache directory; falls back to the system temp folder until it is set
+pub fn get_cache_dir() -> PathBuf {
+    CACHE_DIR
+        .get()
+        .cloned()
+        .unwrap_or_else(|| std::env::temp_dir().join("mods4versus"))
+}
+
+/// Scratch space for merges, wiped on startup. Never inside the game folders.
+pub fn get_scratch_path() -> PathBuf {
+    get_cache_dir().join("scratch")
+}
+
+/// Persistent cache of parsed Workshop VPKs (file tables and optional blobs)
+pub fn get_mod_cache_path() -> PathBuf {
+    get_cache_dir().join("mods")
+}
+
+/// Sets the app config directory (Tauri's `app_config_dir`). Only the first call has effect.
+pub fn init_config_dir(dir: PathBuf) {
+    let _ = CONFIG_DIR.set(dir);
+}
+
+/// Saved user choices (winner rules, ...), kept across sessions
+pub fn get_settings_path() -> PathBuf {
+    CONFIG_DIR
+        .get()
+        .cloned()
+        .unwrap_or_else(get_cache_dir)
+        .join("settings.json")
+}
+
+/// File name (without extension) of the merged VPK
 pub const TEMP_NAME: &str = "pak01_dir";
+
+/// Root-level file inside the merged VPK holding the fingerprint of the merge that produced it
+pub const FINGERPRINT_FILE: &str = "mods4versus_fingerprint.txt";
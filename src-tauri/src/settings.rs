@@ -0,0 +1,195 @@
+//! User choices kept across sessions, stored as JSON in the app config folder
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::install;
+use crate::mod_types::WinnerRule;
+use crate::paths::get_settings_path;
+
+/// Serializes read-modify-write cycles between concurrent commands
+static SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Settings {
+    /// Winner rules of each selection, keyed by `selection_key`
+    pub winner_rules: HashMap<String, Vec<WinnerRule>>,
+    /// Workshop mod IDs by priority: the first one wins conflicts against all others
+    pub load_order: Vec<String>,
+}
+
+impl Settings {
+    /// Reads the saved settings; a missing or unreadable file gives the defaults.
+    pub fn load() -> Self {
+        let _guard = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        read_settings()
+    }
+
+    /// Loads the settings, applies `change` and saves them back.
+    pub fn update<R>(change: impl FnOnce(&mut Settings) -> R) -> Result<R, String> {
+        let _guard = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut settings = read_settings();
+        let result = change(&mut settings);
+        write_settings(&settings)?;
+        Ok(result)
+    }
+
+    /// Winner rules saved for the selection made of `ids`, in any order.
+    pub fn winner_rules_for(&self, ids: &[String]) -> Vec<WinnerRule> {
+        self.winner_rules.get(&selection_key(ids)).cloned().unwrap_or_default()
+    }
+
+    /// Drops uninstalled mods from the load order and adds new ones at the bottom.
+    pub fn sync_load_order(&mut self, installed: &[String]) {
+        let installed_set: HashSet<&String> = installed.iter().collect();
+        self.load_order.retain(|mod_id| installed_set.contains(mod_id));
+
+        let known: HashSet<String> = self.load_order.iter().cloned().collect();
+        let mut added: Vec<String> = installed.iter().filter(|mod_id| !known.contains(*mod_id)).cloned().collect();
+        added.sort();
+        added.dedup();
+        self.load_order.extend(added);
+    }
+
+    /// Moves `mod_id` in the load order: `direction` is "up", "down" or "top".
+    /// Moving the first mod up or the last one down leaves the order as is.
+    pub fn move_mod(&mut self, mod_id: &str, direction: &str) -> Result<(), String> {
+        let order = &mut self.load_order;
+        let Some(position) = order.iter().position(|known| known == mod_id) else {
+            return Err(format!("Error: El mod {} no está instalado.", mod_id));
+        };
+        match direction {
+            "up" if position > 0 => order.swap(position, position - 1),
+            "down" if position + 1 < order.len() => order.swap(position, position + 1),
+            "top" => {
+                let moved = order.remove(position);
+                order.insert(0, moved);
+            }
+            "up" | "down" => {} // Already at that end
+            _ => return Err(format!("Error: Dirección no válida: {}", direction)),
+        }
+        Ok(())
+    }
+
+    /// Syncs the load order with `installed` and returns `ids` in merge order along
+    /// with the winner rules of that selection. Nothing is saved.
+    pub fn ordered_selection(&mut self, ids: &[String], installed: &[String]) -> (Vec<String>, Vec<WinnerRule>) {
+        self.sync_load_order(installed);
+        let ordered = self.merge_order(ids);
+        let rules = self.winner_rules_for(&ordered);
+        (ordered, rules)
+    }
+
+    /// Sorts a selection in merge order: lowest priority first, so the mod highest
+    /// in the load order is applied last and wins. Mods missing from the load order
+    /// go first, keeping their order in `ids`.
+    pub fn merge_order(&self, ids: &[String]) -> Vec<String> {
+        let mut ordered = ids.to_vec();
+        ordered.sort_by_key(|mod_id| {
+            Reverse(self.load_order.iter().position(|known| known == mod_id).unwrap_or(usize::MAX))
+        });
+        ordered
+    }
+}
+
+/// Identifies a selection by its mods, regardless of the order they were picked in.
+pub fn selection_key(ids: &[String]) -> String {
+    let mut sorted: Vec<&str> = ids.iter().map(String::as_str).collect();
+    sorted.sort_unstable();
+    sorted.dedup();
+    sorted.join(",")
+}
+
+fn read_settings() -> Settings {
+    fs::read_to_string(get_settings_path())
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn write_settings(settings: &Settings) -> Result<(), String> {
+    let path = get_settings_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    let data = serde_json::to_vec_pretty(settings).map_err(|e| e.to_string())?;
+    install::write_atomic(&path, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(mod_ids: &[&str]) -> Vec<String> {
+        mod_ids.iter().map(|mod_id| mod_id.to_string()).collect()
+    }
+
+    fn with_order(load_order: &[&str]) -> Settings {
+        Settings {
+            load_order: ids(load_order),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merge_order_puts_the_highest_priority_last() {
+        let settings = with_order(&["c", "a", "b"]);
+        assert_eq!(settings.merge_order(&ids(&["a", "b", "c"])), ids(&["b", "a", "c"]));
+        assert_eq!(settings.merge_order(&ids(&["c", "b"])), ids(&["b", "c"]));
+    }
+
+    #[test]
+    fn merge_order_puts_missing_mods_first() {
+        let settings = with_order(&["a", "b"]);
+        assert_eq!(settings.merge_order(&ids(&["a", "x", "b"])), ids(&["x", "b", "a"]));
+        // Missing mods keep their order in the selection
+        assert_eq!(settings.merge_order(&ids(&["y", "a", "x"])), ids(&["y", "x", "a"]));
+    }
+
+    #[test]
+    fn sync_prunes_removed_mods_and_appends_new_ones() {
+        let mut settings = with_order(&["c", "a", "b"]);
+        settings.sync_load_order(&ids(&["b", "z", "c", "y", "y"]));
+        assert_eq!(settings.load_order, ids(&["c", "b", "y", "z"]));
+    }
+
+    #[test]
+    fn move_mod_stops_at_the_edges() {
+        let mut settings = with_order(&["a", "b", "c"]);
+        settings.move_mod("a", "up").unwrap();
+        settings.move_mod("c", "down").unwrap();
+        assert_eq!(settings.load_order, ids(&["a", "b", "c"]));
+
+        settings.move_mod("b", "up").unwrap();
+        assert_eq!(settings.load_order, ids(&["b", "a", "c"]));
+        settings.move_mod("b", "down").unwrap();
+        assert_eq!(settings.load_order, ids(&["a", "b", "c"]));
+        settings.move_mod("c", "top").unwrap();
+        assert_eq!(settings.load_order, ids(&["c", "a", "b"]));
+        settings.move_mod("c", "top").unwrap();
+        assert_eq!(settings.load_order, ids(&["c", "a", "b"]));
+
+        assert!(settings.move_mod("x", "up").is_err());
+        assert!(settings.move_mod("a", "bottom").is_err());
+        assert_eq!(settings.load_order, ids(&["c", "a", "b"]));
+    }
+
+    #[test]
+    fn ordered_selection_syncs_in_memory() {
+        let mut settings = with_order(&["gone", "b"]);
+        let rules = vec![WinnerRule {
+            pattern: "models/*".to_string(),
+            mod_id: "a".to_string(),
+        }];
+        settings.winner_rules.insert(selection_key(&ids(&["b", "a"])), rules.clone());
+
+        let (ordered, selected_rules) = settings.ordered_selection(&ids(&["b", "a"]), &ids(&["a", "b"]));
+        assert_eq!(ordered, ids(&["a", "b"]));
+        assert_eq!(selected_rules, rules);
+        assert_eq!(settings.load_order, ids(&["b", "a"]));
+    }
+}
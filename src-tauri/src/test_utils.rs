@@ -0,0 +1,48 @@
+//! Helpers shared by the unit tests
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Scratch directory unique to one test, removed when dropped.
+pub struct TestDir(pub PathBuf);
+
+impl TestDir {
+    /// `name` must be unique among all tests, since they run in parallel.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("m4v-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    /// Writes a file inside the directory and returns its path.
+    pub fn file(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Names of the files directly inside the directory, sorted.
+    pub fn listing(&self) -> Vec<String> {
+        listing(&self.0)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Names of the files directly inside `dir`, sorted.
+pub fn listing(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    names
+}
@@ -0,0 +1,579 @@
+//! Native VPK reader for versions 1 and 2.
+//!
+//! Parses the header, the directory tree (including preload bytes and
+//! references to numbered archives) and, for v2, the archive MD5, other MD5
+//! and signature sections. File contents are only read on demand.
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// VPK header signature shared by every version.
+pub const VPK_SIGNATURE: u32 = 0x55aa1234;
+
+/// Archive index used by entries whose data lives inside the directory file itself.
+pub const EMBEDDED_ARCHIVE_INDEX: u16 = 0x7FFF;
+
+/// Terminator written after every tree entry.
+const ENTRY_TERMINATOR: u16 = 0xFFFF;
+
+/// Size in bytes of an entry's fixed part in the tree.
+const TREE_ENTRY_SIZE: usize = 18;
+
+/// Size in bytes of one record in the v2 archive MD5 section.
+const ARCHIVE_MD5_ENTRY_SIZE: usize = 28;
+
+/// Size in bytes of the v2 other MD5 section.
+const OTHER_MD5_SIZE: usize = 48;
+
+/// Errors produced while reading a VPK.
+#[derive(Debug)]
+pub enum VpkError {
+    /// Underlying I/O failure
+    Io(io::Error),
+    /// The file does not start with the VPK signature
+    InvalidSignature(u32),
+    /// Only versions 1 and 2 are supported
+    UnsupportedVersion(u32),
+    /// The file is shorter than its header claims
+    Truncated(&'static str),
+    /// A string in the tree is missing its NUL terminator
+    UnterminatedString { offset: usize },
+    /// A tree entry is not followed by the 0xFFFF terminator
+    InvalidTerminator { path: String },
+    /// A section is malformed (e.g. its size is not a multiple of its record size)
+    InvalidSection(&'static str),
+    /// No entry with this path exists in the VPK
+    NotFound(String),
+    /// The contents read for an entry do not match its CRC32
+    CrcMismatch { path: String },
+    /// The entry is larger than the caller allows
+    TooLarge { path: String, size: u64 },
+}
+
+impl fmt::Display for VpkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VpkError::Io(e) => write!(f, "I/O error: {}", e),
+            VpkError::InvalidSignature(signature) => write!(f, "Invalid VPK signature 0x{:08x}", signature),
+            VpkError::UnsupportedVersion(version) => write!(f, "Unsupported VPK version {}", version),
+            VpkError::Truncated(what) => write!(f, "VPK is truncated ({})", what),
+            VpkError::UnterminatedString { offset } => {
+                write!(f, "Unterminated string in VPK tree at offset {}", offset)
+            }
+            VpkError::InvalidTerminator { path } => write!(f, "Invalid entry terminator for {}", path),
+            VpkError::InvalidSection(section) => write!(f, "Malformed {} section", section),
+            VpkError::NotFound(path) => write!(f, "{} not found in VPK", path),
+            VpkError::CrcMismatch { path } => write!(f, "CRC32 mismatch for {}", path),
+            VpkError::TooLarge { path, size } => write!(f, "{} is too large ({} bytes)", path, size),
+        }
+    }
+}
+
+impl std::error::Error for VpkError {}
+
+impl From<io::Error> for VpkError {
+    fn from(e: io::Error) -> Self {
+        VpkError::Io(e)
+    }
+}
+
+/// Parsed VPK header. Section sizes are zero for version 1.
+#[derive(Clone, Debug, Default)]
+pub struct VpkHeader {
+    pub version: u32,
+    pub tree_size: u32,
+    pub file_data_size: u32,
+    pub archive_md5_size: u32,
+    pub other_md5_size: u32,
+    pub signature_size: u32,
+}
+
+impl VpkHeader {
+    /// Size of the header itself (12 bytes for v1, 28 bytes for v2)
+    pub fn header_size(&self) -> u64 {
+        if self.version == 1 {
+            12
+        } else {
+            28
+        }
+    }
+
+    /// Offset of the embedded data section inside the directory file
+    pub fn data_offset(&self) -> u64 {
+        self.header_size() + self.tree_size as u64
+    }
+}
+
+/// A file entry from the directory tree.
+#[derive(Clone, Debug)]
+pub struct VpkEntry {
+    /// Extension without the dot, `" "` when the file has none
+    pub ext: String,
+    /// Directory using forward slashes, `" "` for the root
+    pub dir: String,
+    /// File name without extension
+    pub name: String,
+    /// CRC32 of the complete contents (preload included)
+    pub crc: u32,
+    /// Bytes stored inline in the tree, before the archive data
+    pub preload: Vec<u8>,
+    /// Numbered archive holding the data, or `EMBEDDED_ARCHIVE_INDEX`
+    pub archive_index: u16,
+    /// Offset of the data inside its archive (or the embedded data section)
+    pub offset: u32,
+    /// Bytes stored in the archive, excluding preload
+    pub length: u32,
+}
+
+impl VpkEntry {
+    /// Full path inside the VPK, e.g. `materials/models/survivors/coach.vtf`
+    pub fn full_path(&self) -> String {
+        entry_path(&self.dir, &self.name, &self.ext)
+    }
+
+    /// Total size of the contents (preload plus archive data)
+    pub fn size(&self) -> u64 {
+        self.preload.len() as u64 + self.length as u64
+    }
+
+    /// Whether the data lives in the directory file rather than a numbered archive
+    pub fn is_embedded(&self) -> bool {
+        self.archive_index == EMBEDDED_ARCHIVE_INDEX
+    }
+}
+
+/// One record of the v2 archive MD5 section.
+#[derive(Clone, Debug)]
+pub struct ArchiveMd5 {
+    pub archive_index: u32,
+    pub offset: u32,
+    pub length: u32,
+    pub md5: [u8; 16],
+}
+
+/// The v2 other MD5 section.
+#[derive(Clone, Debug)]
+pub struct OtherMd5 {
+    /// MD5 of the directory tree
+    pub tree_md5: [u8; 16],
+    /// MD5 of the archive MD5 section
+    pub archive_md5_section_md5: [u8; 16],
+    /// MD5 of the directory file up to (and including) the two checksums above
+    pub whole_file_md5: [u8; 16],
+}
+
+/// The v2 signature section.
+#[derive(Clone, Debug)]
+pub struct VpkSignature {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// An opened VPK directory file with its parsed tree and sections.
+#[derive(Debug)]
+pub struct VpkArchive {
+    path: PathBuf,
+    pub header: VpkHeader,
+    pub entries: Vec<VpkEntry>,
+    pub archive_md5s: Vec<ArchiveMd5>,
+    pub other_md5: Option<OtherMd5>,
+    pub signature: Option<VpkSignature>,
+}
+
+impl VpkArchive {
+    /// Opens and parses a VPK directory file. No file contents are read.
+    pub fn open(path: &Path) -> Result<Self, VpkError> {
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+
+        let mut fixed = [0u8; 12];
+        read_exact_or(&mut file, &mut fixed, "header")?;
+        let signature = le_u32(&fixed, 0);
+        if signature != VPK_SIGNATURE {
+            return Err(VpkError::InvalidSignature(signature));
+        }
+
+        let mut header = VpkHeader {
+            version: le_u32(&fixed, 4),
+            tree_size: le_u32(&fixed, 8),
+            ..Default::default()
+        };
+        match header.version {
+            1 => {}
+            2 => {
+                let mut extra = [0u8; 16];
+                read_exact_or(&mut file, &mut extra, "header")?;
+                header.file_data_size = le_u32(&extra, 0);
+                header.archive_md5_size = le_u32(&extra, 4);
+                header.other_md5_size = le_u32(&extra, 8);
+                header.signature_size = le_u32(&extra, 12);
+            }
+            version => return Err(VpkError::UnsupportedVersion(version)),
+        }
+
+        if header.data_offset() > file_len {
+            return Err(VpkError::Truncated("tree"));
+        }
+        let mut tree = vec![0u8; header.tree_size as usize];
+        read_exact_or(&mut file, &mut tree, "tree")?;
+        let entries = parse_tree(&tree)?;
+
+        let mut archive = VpkArchive {
+            path: path.to_path_buf(),
+            header,
+            entries,
+            archive_md5s: Vec::new(),
+            other_md5: None,
+            signature: None,
+        };
+        if archive.header.version == 2 {
+            archive.read_v2_sections(&mut file, file_len)?;
+        }
+        Ok(archive)
+    }
+
+    /// Reads the archive MD5, other MD5 and signature sections that follow the embedded data.
+    fn read_v2_sections(&mut self, file: &mut File, file_len: u64) -> Result<(), VpkError> {
+        let header = &self.header;
+        let sections_start = header.data_offset() + header.file_data_size as u64;
+        let sections_end = sections_start
+            + header.archive_md5_size as u64
+            + header.other_md5_size as u64
+            + header.signature_size as u64;
+        if sections_end > file_len {
+            return Err(VpkError::Truncated("v2 sections"));
+        }
+        file.seek(SeekFrom::Start(sections_start))?;
+
+        if !(header.archive_md5_size as usize).is_multiple_of(ARCHIVE_MD5_ENTRY_SIZE) {
+            return Err(VpkError::InvalidSection("archive MD5"));
+        }
+        let mut section = vec![0u8; header.archive_md5_size as usize];
+        read_exact_or(file, &mut section, "archive MD5 section")?;
+        self.archive_md5s = section
+            .chunks_exact(ARCHIVE_MD5_ENTRY_SIZE)
+            .map(|record| ArchiveMd5 {
+                archive_index: le_u32(record, 0),
+                offset: le_u32(record, 4),
+                length: le_u32(record, 8),
+                md5: record[12..28].try_into().unwrap(),
+            })
+            .collect();
+
+        match header.other_md5_size as usize {
+            0 => {}
+            OTHER_MD5_SIZE => {
+                let mut section = [0u8; OTHER_MD5_SIZE];
+                read_exact_or(file, &mut section, "other MD5 section")?;
+                self.other_md5 = Some(OtherMd5 {
+                    tree_md5: section[0..16].try_into().unwrap(),
+                    archive_md5_section_md5: section[16..32].try_into().unwrap(),
+                    whole_file_md5: section[32..48].try_into().unwrap(),
+                });
+            }
+            _ => return Err(VpkError::InvalidSection("other MD5")),
+        }
+
+        if header.signature_size > 0 {
+            let mut section = vec![0u8; header.signature_size as usize];
+            read_exact_or(file, &mut section, "signature section")?;
+            let mut pos = 0usize;
+            let mut take_block = |section: &[u8]| -> Result<Vec<u8>, VpkError> {
+                if pos + 4 > section.len() {
+                    return Err(VpkError::InvalidSection("signature"));
+                }
+                let size = le_u32(section, pos) as usize;
+                pos += 4;
+                let block = section
+                    .get(pos..pos + size)
+                    .ok_or(VpkError::InvalidSection("signature"))?
+                    .to_vec();
+                pos += size;
+                Ok(block)
+            };
+            let public_key = take_block(&section)?;
+            let signature = take_block(&section)?;
+            self.signature = Some(VpkSignature { public_key, signature });
+        }
+        Ok(())
+    }
+
+    /// Finds an entry by its full path, ignoring ASCII case and slash direction
+    pub fn find(&self, path: &str) -> Option<&VpkEntry> {
+        let wanted = path.replace('\\', "/");
+        self.entries
+            .iter()
+            .find(|entry| entry.full_path().eq_ignore_ascii_case(&wanted))
+    }
+
+    /// Returns the file holding `entry`'s archive data and the absolute offset inside it
+    pub fn data_location(&self, entry: &VpkEntry) -> (PathBuf, u64) {
+        if entry.is_embedded() {
+            (self.path.clone(), self.header.data_offset() + entry.offset as u64)
+        } else {
+            (archive_path(&self.path, entry.archive_index), entry.offset as u64)
+        }
+    }
+
+    /// Reads the complete contents of `entry` (preload plus archive data),
+    /// checking them against the entry's CRC32
+    pub fn read_entry(&self, entry: &VpkEntry) -> Result<Vec<u8>, VpkError> {
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        data.extend_from_slice(&entry.preload);
+        if entry.length > 0 {
+            let (archive, offset) = self.data_location(entry);
+            let mut file = File::open(archive)?;
+            file.seek(SeekFrom::Start(offset))?;
+            let start = data.len();
+            data.resize(start + entry.length as usize, 0);
+            read_exact_or(&mut file, &mut data[start..], "entry data")?;
+        }
+        if crc32fast::hash(&data) != entry.crc {
+            return Err(VpkError::CrcMismatch { path: entry.full_path() });
+        }
+        Ok(data)
+    }
+
+    /// Reads the contents of the file at `path`, refusing files over `max_size` bytes
+    /// before anything is allocated (sizes come from the untrusted tree).
+    pub fn read_file(&self, path: &str, max_size: u64) -> Result<Vec<u8>, VpkError> {
+        let entry = self.find(path).ok_or_else(|| VpkError::NotFound(path.to_string()))?;
+        if entry.size() > max_size {
+            return Err(VpkError::TooLarge { path: entry.full_path(), size: entry.size() });
+        }
+        self.read_entry(entry)
+    }
+}
+
+/// Returns the path of numbered archive `index` belonging to a directory VPK.
+/// `.../pak01_dir.vpk` with index 3 becomes `.../pak01_003.vpk`.
+pub fn archive_path(dir_vpk: &Path, index: u16) -> PathBuf {
+    let stem = dir_vpk.file_stem().unwrap_or_default().to_string_lossy();
+    let prefix = stem.strip_suffix("_dir").unwrap_or(&stem);
+    dir_vpk.with_file_name(format!("{}_{:03}.vpk", prefix, index))
+}
+
+/// Joins tree components into a full path. VPKs store `" "` for a missing
+/// extension and for the root directory; both are treated like empty strings.
+pub fn entry_path(dir: &str, name: &str, ext: &str) -> String {
+    let file_name = if ext.is_empty() || ext == " " {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, ext)
+    };
+    if dir.is_empty() || dir == " " {
+        file_name
+    } else {
+        format!("{}/{}", dir, file_name)
+    }
+}
+
+/// Parses the extension -> directory -> file tree.
+fn parse_tree(tree: &[u8]) -> Result<Vec<VpkEntry>, VpkError> {
+    let mut pos = 0usize;
+    let mut entries = Vec::new();
+
+    loop {
+        let ext = read_string(tree, &mut pos)?;
+        if ext.is_empty() {
+            break;
+        }
+        loop {
+            let dir = read_string(tree, &mut pos)?;
+            if dir.is_empty() {
+                break;
+            }
+            loop {
+                let name = read_string(tree, &mut pos)?;
+                if name.is_empty() {
+                    break;
+                }
+                let fixed = tree
+                    .get(pos..pos + TREE_ENTRY_SIZE)
+                    .ok_or(VpkError::Truncated("tree entry"))?;
+                let preload_bytes = le_u16(fixed, 4) as usize;
+                let mut entry = VpkEntry {
+                    ext: ext.clone(),
+                    dir: dir.clone(),
+                    name,
+                    crc: le_u32(fixed, 0),
+                    preload: Vec::new(),
+                    archive_index: le_u16(fixed, 6),
+                    offset: le_u32(fixed, 8),
+                    length: le_u32(fixed, 12),
+                };
+                if le_u16(fixed, 16) != ENTRY_TERMINATOR {
+                    return Err(VpkError::InvalidTerminator { path: entry.full_path() });
+                }
+                pos += TREE_ENTRY_SIZE;
+
+                entry.preload = tree
+                    .get(pos..pos + preload_bytes)
+                    .ok_or(VpkError::Truncated("preload data"))?
+                    .to_vec();
+                pos += preload_bytes;
+                entries.push(entry);
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Reads a NUL-terminated string at `pos`, advancing past the terminator.
+fn read_string(tree: &[u8], pos: &mut usize) -> Result<String, VpkError> {
+    let start = *pos;
+    let end = tree
+        .get(start..)
+        .and_then(|rest| rest.iter().position(|&b| b == 0))
+        .ok_or(VpkError::UnterminatedString { offset: start })?;
+    *pos = start + end + 1;
+    Ok(String::from_utf8_lossy(&tree[start..start + end]).to_string())
+}
+
+fn read_exact_or<R: Read>(reader: &mut R, buffer: &mut [u8], what: &'static str) -> Result<(), VpkError> {
+    reader.read_exact(buffer).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => VpkError::Truncated(what),
+        _ => VpkError::Io(e),
+    })
+}
+
+fn le_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn le_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Writes `bytes` to a temporary directory file and opens it.
+    fn open_bytes(name: &str, bytes: &[u8]) -> Result<VpkArchive, VpkError> {
+        let path = std::env::temp_dir().join(format!("m4v-reader-{}-{}.vpk", name, std::process::id()));
+        fs::write(&path, bytes).unwrap();
+        let result = VpkArchive::open(&path);
+        let _ = fs::remove_file(&path);
+        result
+    }
+
+    /// A v1 or v2 directory file with the given tree; v2 section sizes come from `sections`.
+    fn vpk_bytes(version: u32, tree: &[u8], sections: [u32; 4], tail: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&VPK_SIGNATURE.to_le_bytes());
+        bytes.extend_from_slice(&version.to_le_bytes());
+        bytes.extend_from_slice(&(tree.len() as u32).to_le_bytes());
+        if version == 2 {
+            for size in sections {
+                bytes.extend_from_slice(&size.to_le_bytes());
+            }
+        }
+        bytes.extend_from_slice(tree);
+        bytes.extend_from_slice(tail);
+        bytes
+    }
+
+    /// Tree holding `a/b.vtf` with two preload bytes, ending with `terminator`.
+    fn tree_with_terminator(terminator: u16) -> Vec<u8> {
+        let mut tree = b"vtf\0a\0b\0".to_vec();
+        tree.extend_from_slice(&0x1234_5678u32.to_le_bytes()); // CRC
+        tree.extend_from_slice(&2u16.to_le_bytes()); // preload bytes
+        tree.extend_from_slice(&EMBEDDED_ARCHIVE_INDEX.to_le_bytes());
+        tree.extend_from_slice(&0u32.to_le_bytes()); // offset
+        tree.extend_from_slice(&0u32.to_le_bytes()); // length
+        tree.extend_from_slice(&terminator.to_le_bytes());
+        tree.extend_from_slice(b"xy");
+        tree.extend_from_slice(b"\0\0\0");
+        tree
+    }
+
+    #[test]
+    fn parses_a_valid_tree() {
+        let archive = open_bytes("valid", &vpk_bytes(1, &tree_with_terminator(ENTRY_TERMINATOR), [0; 4], &[])).unwrap();
+        assert_eq!(archive.entries.len(), 1);
+        let entry = &archive.entries[0];
+        assert_eq!(entry.full_path(), "a/b.vtf");
+        assert_eq!(entry.crc, 0x1234_5678);
+        assert_eq!(entry.preload, b"xy");
+        assert!(entry.is_embedded());
+        assert!(matches!(archive.read_file("A/B.vtf", 1), Err(VpkError::TooLarge { size: 2, .. })));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut bytes = vpk_bytes(1, b"\0", [0; 4], &[]);
+        bytes[0] = 0;
+        assert!(matches!(open_bytes("signature", &bytes), Err(VpkError::InvalidSignature(0x55aa1200))));
+
+        assert!(matches!(open_bytes("version", &vpk_bytes(3, b"\0", [0; 4], &[])), Err(VpkError::UnsupportedVersion(3))));
+        assert!(matches!(open_bytes("short", &VPK_SIGNATURE.to_le_bytes()), Err(VpkError::Truncated("header"))));
+
+        // v2 header cut in the middle of its section sizes
+        let mut bytes = vpk_bytes(2, b"", [0; 4], &[]);
+        bytes.truncate(20);
+        assert!(matches!(open_bytes("v2-short", &bytes), Err(VpkError::Truncated("header"))));
+
+        // Tree size pointing past the end of the file
+        let mut bytes = vpk_bytes(1, b"\0", [0; 4], &[]);
+        bytes[8..12].copy_from_slice(&100u32.to_le_bytes());
+        assert!(matches!(open_bytes("tree-size", &bytes), Err(VpkError::Truncated("tree"))));
+    }
+
+    #[test]
+    fn rejects_malformed_trees() {
+        assert!(matches!(
+            open_bytes("unterminated", &vpk_bytes(1, b"vtf", [0; 4], &[])),
+            Err(VpkError::UnterminatedString { offset: 0 })
+        ));
+        assert!(matches!(
+            open_bytes("unterminated-dir", &vpk_bytes(1, b"vtf\0a", [0; 4], &[])),
+            Err(VpkError::UnterminatedString { offset: 4 })
+        ));
+
+        let result = open_bytes("terminator", &vpk_bytes(1, &tree_with_terminator(0), [0; 4], &[]));
+        assert!(matches!(result, Err(VpkError::InvalidTerminator { ref path }) if path == "a/b.vtf"));
+
+        let mut tree = b"vtf\0a\0b\0".to_vec();
+        tree.extend_from_slice(&[0; 10]);
+        assert!(matches!(open_bytes("entry", &vpk_bytes(1, &tree, [0; 4], &[])), Err(VpkError::Truncated("tree entry"))));
+
+        // Preload length claiming more bytes than the tree holds
+        let mut tree = tree_with_terminator(ENTRY_TERMINATOR);
+        tree.truncate(tree.len() - 5);
+        tree[12..14].copy_from_slice(&50u16.to_le_bytes());
+        assert!(matches!(open_bytes("preload", &vpk_bytes(1, &tree, [0; 4], &[])), Err(VpkError::Truncated("preload data"))));
+    }
+
+    #[test]
+    fn rejects_malformed_v2_sections() {
+        let tree = tree_with_terminator(ENTRY_TERMINATOR);
+
+        // Archive MD5 section declared but missing
+        let result = open_bytes("v2-missing", &vpk_bytes(2, &tree, [0, 28, 0, 0], &[]));
+        assert!(matches!(result, Err(VpkError::Truncated("v2 sections"))));
+
+        // Not a multiple of the 28-byte record
+        let result = open_bytes("v2-archive", &vpk_bytes(2, &tree, [0, 27, 0, 0], &[0; 27]));
+        assert!(matches!(result, Err(VpkError::InvalidSection("archive MD5"))));
+
+        let result = open_bytes("v2-other", &vpk_bytes(2, &tree, [0, 0, 10, 0], &[0; 10]));
+        assert!(matches!(result, Err(VpkError::InvalidSection("other MD5"))));
+
+        // Public key block claiming 100 bytes in an 8-byte section
+        let mut signature = 100u32.to_le_bytes().to_vec();
+        signature.extend_from_slice(&[0; 4]);
+        let result = open_bytes("v2-signature", &vpk_bytes(2, &tree, [0, 0, 0, 8], &signature));
+        assert!(matches!(result, Err(VpkError::InvalidSection("signature"))));
+
+        let mut sections = vec![0u8; 28 + OTHER_MD5_SIZE];
+        sections[0] = 3; // archive index
+        let archive = open_bytes("v2-valid", &vpk_bytes(2, &tree, [0, 28, OTHER_MD5_SIZE as u32, 0], &sections)).unwrap();
+        assert_eq!(archive.archive_md5s.len(), 1);
+        assert_eq!(archive.archive_md5s[0].archive_index, 3);
+        assert!(archive.other_md5.is_some());
+    }
+}
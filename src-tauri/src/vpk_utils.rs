@@ -1,159 +1,1595 @@
-use std::fs::{self, File};
-use std::io::Write;
-use std::path::Path;
-use std::collections::HashMap;
-use valve_pak::VPK;
-
-/// Extracts all files from a VPK to a destination directory using valve_pak.
-/// Skips root-level files (like addoninfo.txt) to avoid conflicts in merged VPKs.
-pub fn extract_vpk(vpk_path: &Path, out_dir: &Path) -> Result<(), String> {
-    let vpk = VPK::open(vpk_path).map_err(|e| format!("Failed to open VPK: {}", e))?;
-    
-    // Iterate through all files in the VPK
-    for path in vpk.file_paths() {
-        // Skip root-level files (those without '/' in path)
-        // Root files like addoninfo.txt should NOT be included in merged VPK
-        if !path.contains('/') && !path.contains('\\') {
-            continue;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use md5::{Digest, Md5};
+
+use crate::filters::check_entry_path;
+use crate::install::Transaction;
+use crate::mod_types::{ExtractSummary, FileCheck, VerifyReport};
+pub use crate::vpk_reader::archive_path;
+use crate::vpk_reader::{entry_path, ArchiveMd5, VpkArchive, EMBEDDED_ARCHIVE_INDEX, VPK_SIGNATURE};
+
+/// Default maximum size of each numbered archive (`pak01_000.vpk`, ...).
+/// Matches the ~200 MB chunks produced by Valve's own vpk tool.
+pub const DEFAULT_ARCHIVE_SIZE: u64 = 200 * 1024 * 1024;
+
+/// Size of the archive data covered by each record of the v2 archive MD5 section.
+const ARCHIVE_MD5_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Size in bytes of the v2 other MD5 section (tree, archive MD5 section and whole file).
+const OTHER_MD5_SIZE: u32 = 48;
+
+/// Options controlling how `pack_vpk` lays out its output.
+#[derive(Clone, Debug)]
+pub struct PackOptions {
+    /// Maximum bytes per numbered archive. Packs whose data fits in a single
+    /// chunk are written as one self-contained `_dir.vpk`.
+    pub archive_size: u64,
+    /// VPK version to write: 1 (L4D2) or 2 (with MD5 checksum sections)
+    pub version: u32,
+    /// Files up to this many bytes are stored as preload data inside the tree,
+    /// like Valve's vpk tool does for small `.vmt`/`.txt` files. 0 disables it.
+    pub preload_threshold: u64,
+    /// Store byte-identical files once and point every duplicate entry at that blob
+    pub deduplicate: bool,
+    /// Always store data in numbered archives, even when it would fit in the
+    /// directory file. Required for `patch_vpk` to update the pack later.
+    pub split_archives: bool,
+}
+
+/// Statistics about a pack written by `pack_vpk`.
+#[derive(Clone, Debug, Default)]
+pub struct PackStats {
+    /// Entries that share their data with an identical earlier entry
+    pub deduplicated_files: usize,
+    /// Bytes not written thanks to deduplication
+    pub bytes_saved: u64,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        Self {
+            archive_size: DEFAULT_ARCHIVE_SIZE,
+            version: 1,
+            preload_threshold: 0,
+            deduplicate: true,
+            split_archives: false,
         }
-        
-        // Read file content
-        let mut file = vpk.get_file(&path).map_err(|e| format!("Failed to get {}: {}", path, e))?;
-        let data = file.read_all().map_err(|e| format!("Failed to read {}: {}", path, e))?;
-        
-        // Create output path
-        let out_path = out_dir.join(&path);
-        
-        // Create parent directories
-        if let Some(parent) = out_path.parent() {
-            fs::create_dir_all(parent).map_err(|e| format!("Failed to create dir: {}", e))?;
-        }
-        
-        // Write file
-        let mut out_file = File::create(&out_path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
-        out_file.write_all(&data).map_err(|e| format!("Failed to write {}: {}", path, e))?;
     }
-    
+}
+
+/// Lists every file of a multi-archive VPK set that exists on disk:
+/// the directory VPK followed by its numbered archives in order.
+pub fn vpk_set_files(dir_vpk: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if dir_vpk.exists() {
+        files.push(dir_vpk.to_path_buf());
+    }
+    for index in 0..EMBEDDED_ARCHIVE_INDEX {
+        let chunk = archive_path(dir_vpk, index);
+        if !chunk.exists() {
+            break;
+        }
+        files.push(chunk);
+    }
+    files
+}
+
+/// Removes a directory VPK and all of its numbered archives.
+pub fn remove_vpk_set(dir_vpk: &Path) -> Result<(), String> {
+    for file in vpk_set_files(dir_vpk) {
+        fs::remove_file(&file).map_err(|e| format!("Failed to remove {}: {}", file.display(), e))?;
+    }
     Ok(())
 }
 
-/// Packs a directory into a VPK Version 1 file.
-/// Uses proper CRC32 checksums for L4D2 compatibility.
-/// NOTE: valve_pak creates v2 which L4D2 can't read, so we must use native implementation.
-pub fn pack_vpk_v1(content_dir: &Path, output_path: &Path) -> Result<(), String> {
-    let mut file_entries: Vec<(String, String, String, std::path::PathBuf, Vec<u8>)> = Vec::new();
-
-    // Walk directory to gather files with their contents
-    fn visit_dirs_collect(dir: &Path, base: &Path, entries: &mut Vec<(String, String, String, std::path::PathBuf, Vec<u8>)>) -> Result<(), String> {
-        if dir.is_dir() {
-            for entry in fs::read_dir(dir).map_err(|e| e.to_string())? {
-                let entry = entry.map_err(|e| e.to_string())?;
-                let path = entry.path();
-                if path.is_dir() {
-                    visit_dirs_collect(&path, base, entries)?;
-                } else if path.is_file() {
-                    let rel_path = path.strip_prefix(base).unwrap();
-                    let extension = rel_path.extension().unwrap_or_default().to_string_lossy().to_string();
-                    let file_stem = rel_path.file_stem().unwrap_or_default().to_string_lossy().to_string();
-                    let parent = rel_path.parent().unwrap_or(Path::new(""));
-                    let dir_path = if parent.as_os_str().is_empty() {
-                        " ".to_string()
-                    } else {
-                        parent.to_string_lossy().replace('\\', "/")
-                    };
-                    let data = fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
-                    entries.push((extension, dir_path, file_stem, path.clone(), data));
-                }
+/// Size of the buffer used when streaming file contents into the pack.
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Most file data the worker pool reads ahead of the writer while packing.
+const READ_AHEAD_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Files larger than this are streamed by the writer instead of being read ahead.
+const READ_AHEAD_MAX_FILE: u64 = 8 * 1024 * 1024;
+
+/// Upper bound on worker threads; reads past this point are limited by the disk.
+const MAX_WORKERS: usize = 8;
+
+/// Runs `job` over `items` on a pool of worker threads and returns the results in
+/// input order, so callers stay deterministic regardless of scheduling.
+///
+/// `init` creates per-worker state (e.g. a read buffer) passed to every job of that worker.
+pub fn parallel_map<T, S, R>(
+    items: &[T],
+    init: impl Fn() -> S + Sync,
+    job: impl Fn(&mut S, &T) -> R + Sync,
+) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+{
+    let workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(MAX_WORKERS)
+        .min(items.len());
+    if workers <= 1 {
+        let mut state = init();
+        return items.iter().map(|item| job(&mut state, item)).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<R>> = items.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut state = init();
+                    let mut done = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= items.len() {
+                            break;
+                        }
+                        done.push((index, job(&mut state, &items[index])));
+                    }
+                    done
+                })
+            })
+            .collect();
+        for handle in handles {
+            for (index, result) in handle.join().expect("worker thread panicked") {
+                results[index] = Some(result);
             }
         }
-        Ok(())
+    });
+    results.into_iter().map(|result| result.expect("every item is processed")).collect()
+}
+
+/// Where the contents of a `PackEntry` come from.
+#[derive(Clone, Debug)]
+pub enum EntrySource {
+    /// An entry inside another VPK: `preload` bytes stored in its tree, followed by
+    /// the rest of the data at `offset` inside `archive`
+    Vpk {
+        archive: PathBuf,
+        offset: u64,
+        preload: Vec<u8>,
+    },
+    /// A content-addressed copy stored in the mod cache
+    Blob(PathBuf),
+    /// Contents generated by the app itself (e.g. the merge fingerprint)
+    Memory(Vec<u8>),
+}
+
+/// A file scheduled for packing. Only metadata is kept in memory;
+/// contents are streamed from `source` when the data section is written.
+#[derive(Clone, Debug)]
+pub struct PackEntry {
+    /// Extension without the dot; empty or `" "` when the file has none
+    pub ext: String,
+    /// Directory inside the VPK using forward slashes; empty or `" "` for the root
+    pub dir: String,
+    /// File name without extension
+    pub name: String,
+    /// Where the contents are read from
+    pub source: EntrySource,
+    /// Size of the contents in bytes (preload included)
+    pub size: u64,
+    /// CRC32 of the contents when already known (e.g. from the source VPK's tree)
+    pub crc: Option<u32>,
+    /// MD5 of the contents when already known (e.g. from the mod cache)
+    pub md5: Option<[u8; 16]>,
+}
+
+impl PackEntry {
+    /// Creates an entry for in-memory contents at `path` (e.g. `scripts/foo.txt`).
+    pub fn from_memory(path: &str, data: Vec<u8>) -> Self {
+        let (dir, file_name) = path.rsplit_once('/').unwrap_or(("", path));
+        let (name, ext) = file_name.rsplit_once('.').unwrap_or((file_name, ""));
+        Self {
+            ext: ext.to_string(),
+            dir: dir.to_string(),
+            name: name.to_string(),
+            size: data.len() as u64,
+            crc: Some(crc32fast::hash(&data)),
+            md5: Some(Md5::digest(&data).into()),
+            source: EntrySource::Memory(data),
+        }
     }
 
-    visit_dirs_collect(content_dir, content_dir, &mut file_entries)?;
+    /// Full path inside the VPK, e.g. `materials/models/survivors/coach.vtf`
+    pub fn full_path(&self) -> String {
+        entry_path(&self.dir, &self.name, &self.ext)
+    }
 
-    // Sort for deterministic output
-    file_entries.sort_by(|a, b| {
-        a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)).then_with(|| a.2.cmp(&b.2))
-    });
+    /// Rewrites the path in the form the engine looks files up by: lowercase, forward
+    /// slashes and no leading or trailing slash. Returns the path as it was before.
+    pub fn normalize_path(&mut self) -> String {
+        let original = self.full_path();
+        self.dir = self.dir.replace('\\', "/").trim_matches('/').to_ascii_lowercase();
+        self.name = self.name.to_ascii_lowercase();
+        self.ext = self.ext.to_ascii_lowercase();
+        original
+    }
+}
 
-    // Group by extension -> path -> files
-    let mut files_by_ext: HashMap<String, HashMap<String, Vec<(String, Vec<u8>)>>> = HashMap::new();
-    for (ext, dir, name, _, data) in file_entries {
-        files_by_ext
-            .entry(ext)
-            .or_default()
-            .entry(dir)
-            .or_default()
-            .push((name, data));
+/// Where an entry's data ends up once the layout has been computed.
+#[derive(Clone, Debug, Default)]
+struct EntryLayout {
+    crc: u32,
+    archive_index: u16,
+    offset: u32,
+    /// Contents inlined in the tree; non-empty only for files under the preload threshold
+    preload: Vec<u8>,
+    /// Data is shared with an identical earlier entry, so nothing is written for this one
+    shared: bool,
+}
+
+/// Reads the directory tree of a VPK (v1 or v2) and returns an entry for every file,
+/// pointing at its data inside the source archive(s). No file contents are read.
+pub fn index_vpk(vpk_path: &Path) -> Result<Vec<PackEntry>, String> {
+    let archive = VpkArchive::open(vpk_path)
+        .map_err(|e| format!("Failed to open {}: {}", vpk_path.display(), e))?;
+
+    let entries = archive
+        .entries
+        .iter()
+        .map(|entry| {
+            let (archive_file, offset) = archive.data_location(entry);
+            PackEntry {
+                ext: entry.ext.clone(),
+                dir: entry.dir.clone(),
+                name: entry.name.clone(),
+                source: EntrySource::Vpk {
+                    archive: archive_file,
+                    offset,
+                    preload: entry.preload.clone(),
+                },
+                size: entry.size(),
+                crc: Some(entry.crc),
+                md5: None,
+            }
+        })
+        .collect();
+    Ok(entries)
+}
+
+/// Extracts every file of a VPK (v1 or v2, preload and numbered archives included)
+/// into `out_dir`, streaming contents without loading whole files in memory.
+/// Root-level files like addoninfo.txt are only written when `include_root_files` is set.
+/// `on_progress(done, total)` is called after each file.
+pub fn extract_vpk(
+    vpk_path: &Path,
+    out_dir: &Path,
+    include_root_files: bool,
+    mut on_progress: impl FnMut(usize, usize),
+) -> Result<ExtractSummary, String> {
+    let entries = index_vpk(vpk_path)?;
+    let mut summary = ExtractSummary {
+        out_dir: out_dir.display().to_string(),
+        ..Default::default()
+    };
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let total = entries.len();
+
+    // Paths that would land outside out_dir are refused before anything is written
+    let unsafe_paths: Vec<bool> = entries
+        .iter()
+        .map(|entry| check_entry_path(&entry.full_path()).is_err())
+        .collect();
+
+    for (done, entry) in entries.iter().enumerate() {
+        let is_root = entry.dir.is_empty() || entry.dir == " ";
+        if unsafe_paths[done] {
+            summary.rejected_paths.push(entry.full_path());
+        } else if is_root && !include_root_files {
+            summary.skipped_root_files += 1;
+        } else {
+            let out_path = out_dir.join(entry.full_path());
+
+            // Create parent directories
+            if let Some(parent) = out_path.parent() {
+                fs::create_dir_all(parent).map_err(|e| format!("Failed to create dir: {}", e))?;
+            }
+
+            let out_file = File::create(&out_path)
+                .map_err(|e| format!("Failed to create {}: {}", out_path.display(), e))?;
+            let mut out_file = BufWriter::new(out_file);
+            stream_entry(entry, &mut out_file, &mut buffer)?;
+            out_file
+                .flush()
+                .map_err(|e| format!("Failed to write {}: {}", out_path.display(), e))?;
+
+            summary.files_written += 1;
+            summary.bytes_written += entry.size;
+        }
+        on_progress(done + 1, total);
     }
 
-    // Build tree and collect file data
+    Ok(summary)
+}
+
+/// Serializes the directory tree for entries already sorted by (ext, dir, name).
+/// `layouts[i]` holds the archive placement and CRC of `entries[i]`.
+fn build_tree(entries: &[PackEntry], layouts: &[EntryLayout]) -> Vec<u8> {
     let mut tree_buffer: Vec<u8> = Vec::new();
-    let mut data_buffer: Vec<u8> = Vec::new();
-    let mut current_offset: u32 = 0;
+    let mut current_ext: Option<&str> = None;
+    let mut current_dir: Option<&str> = None;
 
-    let mut sorted_exts: Vec<_> = files_by_ext.keys().cloned().collect();
-    sorted_exts.sort();
+    for (entry, layout) in entries.iter().zip(layouts) {
+        if current_ext != Some(entry.ext.as_str()) {
+            if current_ext.is_some() {
+                tree_buffer.push(0); // End of filenames
+                tree_buffer.push(0); // End of paths
+            }
+            // Extension
+            tree_buffer.extend_from_slice(entry.ext.as_bytes());
+            tree_buffer.push(0);
+            current_ext = Some(&entry.ext);
+            current_dir = None;
+        }
+        if current_dir != Some(entry.dir.as_str()) {
+            if current_dir.is_some() {
+                tree_buffer.push(0); // End of filenames
+            }
+            // Path
+            tree_buffer.extend_from_slice(entry.dir.as_bytes());
+            tree_buffer.push(0);
+            current_dir = Some(&entry.dir);
+        }
 
-    for ext in sorted_exts {
-        // Extension
-        tree_buffer.extend_from_slice(ext.as_bytes());
+        // Filename
+        tree_buffer.extend_from_slice(entry.name.as_bytes());
         tree_buffer.push(0);
 
-        let paths = files_by_ext.get(&ext).unwrap();
-        let mut sorted_paths: Vec<_> = paths.keys().cloned().collect();
-        sorted_paths.sort();
+        // Entry data (18 bytes)
+        let preload_bytes: u16 = layout.preload.len() as u16;
+        let entry_length: u32 = (entry.size - layout.preload.len() as u64) as u32;
+        let terminator: u16 = 0xFFFF;
 
-        for path in sorted_paths {
-            // Path
-            tree_buffer.extend_from_slice(path.as_bytes());
-            tree_buffer.push(0);
+        tree_buffer.extend_from_slice(&layout.crc.to_le_bytes());
+        tree_buffer.extend_from_slice(&preload_bytes.to_le_bytes());
+        tree_buffer.extend_from_slice(&layout.archive_index.to_le_bytes());
+        tree_buffer.extend_from_slice(&layout.offset.to_le_bytes());
+        tree_buffer.extend_from_slice(&entry_length.to_le_bytes());
+        tree_buffer.extend_from_slice(&terminator.to_le_bytes());
 
-            let files = paths.get(&path).unwrap();
-            for (filename, data) in files {
-                // Filename
-                tree_buffer.extend_from_slice(filename.as_bytes());
-                tree_buffer.push(0);
-
-                // Entry data (18 bytes)
-                let crc = crc32fast::hash(&data);
-                let preload_bytes: u16 = 0;
-                let archive_index: u16 = 0x7FFF; // Data embedded in this file
-                let entry_offset: u32 = current_offset;
-                let entry_length: u32 = data.len() as u32;
-                let terminator: u16 = 0xFFFF;
-
-                tree_buffer.extend_from_slice(&crc.to_le_bytes());
-                tree_buffer.extend_from_slice(&preload_bytes.to_le_bytes());
-                tree_buffer.extend_from_slice(&archive_index.to_le_bytes());
-                tree_buffer.extend_from_slice(&entry_offset.to_le_bytes());
-                tree_buffer.extend_from_slice(&entry_length.to_le_bytes());
-                tree_buffer.extend_from_slice(&terminator.to_le_bytes());
-
-                // Append file data
-                data_buffer.extend_from_slice(&data);
-                current_offset += entry_length;
-            }
-            tree_buffer.push(0); // End of filenames
-        }
+        // Preload data follows the entry directly
+        tree_buffer.extend_from_slice(&layout.preload);
+    }
+
+    if current_ext.is_some() {
+        tree_buffer.push(0); // End of filenames
         tree_buffer.push(0); // End of paths
     }
     tree_buffer.push(0); // End of extensions
+    tree_buffer
+}
+
+/// Streams the contents of `entry` into `out`, returning the CRC32 of the bytes copied.
+/// Fails if the source no longer matches the size recorded when the tree was laid out.
+fn stream_entry<W: Write>(entry: &PackEntry, out: &mut W, buffer: &mut [u8]) -> Result<u32, String> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut copied: u64 = 0;
+
+    let (path, mut source): (&Path, Box<dyn Read + '_>) = match &entry.source {
+        EntrySource::Vpk { archive, offset, preload } => {
+            hasher.update(preload);
+            out.write_all(preload).map_err(|e| e.to_string())?;
+            copied += preload.len() as u64;
+
+            // Preload-only entries may point at an archive that doesn't exist
+            let remaining = entry.size.saturating_sub(copied);
+            if remaining == 0 {
+                (archive, Box::new(io::empty()))
+            } else {
+                let mut file = File::open(archive)
+                    .map_err(|e| format!("Failed to read {}: {}", archive.display(), e))?;
+                file.seek(SeekFrom::Start(*offset)).map_err(|e| e.to_string())?;
+                (archive, Box::new(file.take(remaining)))
+            }
+        }
+        EntrySource::Blob(blob) => {
+            let file = File::open(blob).map_err(|e| format!("Failed to read {}: {}", blob.display(), e))?;
+            (blob, Box::new(file.take(entry.size)))
+        }
+        EntrySource::Memory(data) => (Path::new("<memory>"), Box::new(data.as_slice())),
+    };
+
+    loop {
+        let read = source
+            .read(buffer)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        out.write_all(&buffer[..read]).map_err(|e| e.to_string())?;
+        copied += read as u64;
+    }
+
+    if copied != entry.size {
+        return Err(format!("{} changed or is truncated ({})", entry.full_path(), path.display()));
+    }
+    Ok(hasher.finalize())
+}
+
+/// Reads and hashes the leading run of small entries in `indexes` on the worker pool,
+/// up to `READ_AHEAD_BYTES` in total. Returns nothing when the first entry is large.
+fn read_ahead(entries: &[PackEntry], indexes: &[usize]) -> Result<Vec<(Vec<u8>, u32)>, String> {
+    let mut bytes = 0;
+    let run = indexes
+        .iter()
+        .take_while(|&&index| {
+            let size = entries[index].size;
+            if size > READ_AHEAD_MAX_FILE || bytes + size > READ_AHEAD_BYTES {
+                return false;
+            }
+            bytes += size;
+            true
+        })
+        .count();
+
+    parallel_map(
+        &indexes[..run],
+        || vec![0u8; COPY_BUFFER_SIZE],
+        |buffer, &index| {
+            let mut data = Vec::with_capacity(entries[index].size as usize);
+            let crc = stream_entry(&entries[index], &mut data, buffer)?;
+            Ok((data, crc))
+        },
+    )
+    .into_iter()
+    .collect()
+}
+
+/// Normalizes entries for the tree and sorts them for deterministic output.
+fn prepare_entries(entries: &mut [PackEntry]) {
+    // A bare NUL would end the extension/path list, so empty components are stored as " "
+    for entry in entries.iter_mut() {
+        if entry.ext.is_empty() {
+            entry.ext = " ".to_string();
+        }
+        if entry.dir.is_empty() {
+            entry.dir = " ".to_string();
+        }
+    }
+
+    entries.sort_by(|a, b| {
+        a.ext.cmp(&b.ext).then_with(|| a.dir.cmp(&b.dir)).then_with(|| a.name.cmp(&b.name))
+    });
+}
+
+/// Copies the contents of `entry` into `out` and returns their CRC32.
+pub fn copy_entry<W: Write>(entry: &PackEntry, out: &mut W) -> Result<u32, String> {
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    stream_entry(entry, out, &mut buffer)
+}
+
+/// Adapts an MD5 hasher to `Write` so entries can be streamed into it.
+struct Md5Sink(Md5);
+
+impl Write for Md5Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Finds entries whose contents are byte-identical to an earlier entry.
+/// Only entries sharing their size and CRC32 with another one are read, so unique files
+/// cost nothing. Entries without a known CRC32 are compared with every entry of their size.
+/// Returns, for each entry, the index of the first identical entry and its CRC32.
+fn find_duplicates(
+    entries: &[PackEntry],
+    is_candidate: impl Fn(&PackEntry) -> bool,
+) -> Result<Vec<Option<(usize, u32)>>, String> {
+    let mut size_counts: HashMap<u64, usize> = HashMap::new();
+    let mut crc_counts: HashMap<(u64, u32), usize> = HashMap::new();
+    let mut sizes_without_crc: HashSet<u64> = HashSet::new();
+    for entry in entries.iter().filter(|entry| is_candidate(entry)) {
+        *size_counts.entry(entry.size).or_default() += 1;
+        match entry.crc {
+            Some(crc) => *crc_counts.entry((entry.size, crc)).or_default() += 1,
+            None => {
+                sizes_without_crc.insert(entry.size);
+            }
+        }
+    }
+    let may_have_copy = |entry: &PackEntry| {
+        if size_counts.get(&entry.size).copied().unwrap_or(0) < 2 {
+            return false;
+        }
+        match entry.crc {
+            Some(crc) if !sizes_without_crc.contains(&entry.size) => {
+                crc_counts.get(&(entry.size, crc)).copied().unwrap_or(0) >= 2
+            }
+            _ => true,
+        }
+    };
+
+    // Hash on the worker pool (unless already known), then pick the first copy in tree order
+    let candidates: Vec<usize> = (0..entries.len())
+        .filter(|&index| is_candidate(&entries[index]) && may_have_copy(&entries[index]))
+        .collect();
+    let hashes = parallel_map(
+        &candidates,
+        || vec![0u8; COPY_BUFFER_SIZE],
+        |buffer, &index| {
+            if let (Some(crc), Some(md5)) = (entries[index].crc, entries[index].md5) {
+                return Ok((crc, md5));
+            }
+            let mut sink = Md5Sink(Md5::new());
+            let crc = stream_entry(&entries[index], &mut sink, buffer)?;
+            Ok::<_, String>((crc, <[u8; 16]>::from(sink.0.finalize())))
+        },
+    );
+
+    let mut first_by_content: HashMap<(u64, u32, [u8; 16]), usize> = HashMap::new();
+    let mut duplicates = vec![None; entries.len()];
+    for (index, hash) in candidates.into_iter().zip(hashes) {
+        let (crc, md5) = hash?;
+        let key = (entries[index].size, crc, md5);
+        match first_by_content.get(&key) {
+            Some(&first) => duplicates[index] = Some((first, crc)),
+            None => {
+                first_by_content.insert(key, index);
+            }
+        }
+    }
+    Ok(duplicates)
+}
+
+/// Writes one numbered archive, collecting the v2 archive MD5 records for its data.
+struct ArchiveWriter {
+    index: u16,
+    out: BufWriter<File>,
+    track_md5: bool,
+    chunk_start: u64,
+    chunk_len: u64,
+    chunk_md5: Md5,
+    records: Vec<ArchiveMd5>,
+}
+
+impl ArchiveWriter {
+    fn create(path: &Path, index: u16, track_md5: bool) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        Ok(Self::new(file, index, track_md5))
+    }
+
+    /// Opens archive `index` for appending, creating it if needed. MD5 records are
+    /// not tracked: they would only cover the appended bytes.
+    fn open_append(path: &Path, index: u16) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        Ok(Self::new(file, index, false))
+    }
+
+    fn new(file: File, index: u16, track_md5: bool) -> Self {
+        Self {
+            index,
+            out: BufWriter::new(file),
+            track_md5,
+            chunk_start: 0,
+            chunk_len: 0,
+            chunk_md5: Md5::new(),
+            records: Vec::new(),
+        }
+    }
+
+    fn hash(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = ((ARCHIVE_MD5_CHUNK_SIZE - self.chunk_len) as usize).min(data.len());
+            self.chunk_md5.update(&data[..take]);
+            self.chunk_len += take as u64;
+            data = &data[take..];
+            if self.chunk_len == ARCHIVE_MD5_CHUNK_SIZE {
+                self.finish_chunk();
+            }
+        }
+    }
+
+    fn finish_chunk(&mut self) {
+        if self.chunk_len == 0 {
+            return;
+        }
+        self.records.push(ArchiveMd5 {
+            archive_index: self.index as u32,
+            offset: self.chunk_start as u32,
+            length: self.chunk_len as u32,
+            md5: std::mem::take(&mut self.chunk_md5).finalize().into(),
+        });
+        self.chunk_start += self.chunk_len;
+        self.chunk_len = 0;
+    }
+
+    /// Flushes the archive and returns its MD5 records (empty unless tracking).
+    fn finish(mut self) -> Result<Vec<ArchiveMd5>, String> {
+        self.finish_chunk();
+        let file = self.out.into_inner().map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())?;
+        Ok(self.records)
+    }
+}
+
+impl Write for ArchiveWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(buf)?;
+        if self.track_md5 {
+            self.hash(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Serializes the VPK header. The v2 section sizes are ignored for version 1.
+fn build_header(version: u32, tree_size: u32, file_data_size: u32, archive_md5_size: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(28);
+    header.extend_from_slice(&VPK_SIGNATURE.to_le_bytes());
+    header.extend_from_slice(&version.to_le_bytes());
+    header.extend_from_slice(&tree_size.to_le_bytes());
+    if version == 2 {
+        let signature_size: u32 = 0;
+        header.extend_from_slice(&file_data_size.to_le_bytes());
+        header.extend_from_slice(&archive_md5_size.to_le_bytes());
+        header.extend_from_slice(&OTHER_MD5_SIZE.to_le_bytes());
+        header.extend_from_slice(&signature_size.to_le_bytes());
+    }
+    header
+}
+
+/// Packs `entries` into a VPK file with bounded memory.
+/// Uses proper CRC32 checksums for L4D2 compatibility.
+/// NOTE: L4D2 can't read v2 directory files, so version 1 is the default;
+/// version 2 adds the archive MD5 and other MD5 sections expected by newer branches.
+///
+/// The tree is laid out from entry sizes alone and written with placeholder CRCs,
+/// then every entry is streamed into the data section while its CRC32 is computed,
+/// and finally the header and tree are rewritten in place with the real values.
+///
+/// When the packed data exceeds `options.archive_size`, file data is split into
+/// numbered archives (`pak01_000.vpk`, `pak01_001.vpk`, ...) next to `output_path`
+/// and the directory file only holds the tree.
+///
+/// With `options.deduplicate`, byte-identical files are written once and every
+/// duplicate tree entry points at the same offset/length.
+pub fn pack_vpk(mut entries: Vec<PackEntry>, output_path: &Path, options: &PackOptions) -> Result<PackStats, String> {
+    if options.version != 1 && options.version != 2 {
+        return Err(format!("Unsupported VPK version {}", options.version));
+    }
+
+    prepare_entries(&mut entries);
+
+    // Small files are inlined in the tree as preload data (a u16 length)
+    let preload_threshold = options.preload_threshold.min(u16::MAX as u64);
+    let is_preloaded = |entry: &PackEntry| entry.size > 0 && entry.size <= preload_threshold;
+
+    // Identical files are stored once; duplicates reuse the first copy's data
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let duplicates = if options.deduplicate {
+        find_duplicates(&entries, |entry| entry.size > 0 && !is_preloaded(entry))?
+    } else {
+        vec![None; entries.len()]
+    };
+    let mut stats = PackStats::default();
+
+    // Offsets are 32-bit, so no single archive may grow past u32::MAX
+    let archive_size = options.archive_size.clamp(1, u32::MAX as u64);
+    let total_size: u64 = entries
+        .iter()
+        .zip(&duplicates)
+        .filter(|(entry, duplicate)| !is_preloaded(entry) && duplicate.is_none())
+        .map(|(entry, _)| entry.size)
+        .sum();
+    let multi_archive = options.split_archives || total_size > archive_size;
+
+    // Preloaded files are the only ones read before the data section is written
+    let preloaded: Vec<usize> = (0..entries.len()).filter(|&index| is_preloaded(&entries[index])).collect();
+    let mut preloads = read_ahead(&entries, &preloaded)?.into_iter();
+
+    // Lay out every entry in tree order
+    let mut layouts: Vec<EntryLayout> = Vec::with_capacity(entries.len());
+    let mut archive_index: u16 = 0;
+    let mut archive_used: u64 = 0;
+
+    for (entry, duplicate) in entries.iter().zip(&duplicates) {
+        // Preloaded files have no archive data, so they don't reference any archive
+        if is_preloaded(entry) {
+            let (preload, crc) = match preloads.next() {
+                Some(loaded) => loaded,
+                None => {
+                    let mut preload = Vec::with_capacity(entry.size as usize);
+                    let crc = stream_entry(entry, &mut preload, &mut buffer)?;
+                    (preload, crc)
+                }
+            };
+            layouts.push(EntryLayout {
+                crc,
+                archive_index: EMBEDDED_ARCHIVE_INDEX,
+                offset: 0,
+                preload,
+                shared: false,
+            });
+            continue;
+        }
+
+        if let Some((first, crc)) = *duplicate {
+            let first_layout = &layouts[first];
+            layouts.push(EntryLayout {
+                crc,
+                archive_index: first_layout.archive_index,
+                offset: first_layout.offset,
+                preload: Vec::new(),
+                shared: true,
+            });
+            stats.deduplicated_files += 1;
+            stats.bytes_saved += entry.size;
+            continue;
+        }
+
+        if entry.size > u32::MAX as u64 {
+            return Err(format!("{} is too large for a VPK entry", entry.full_path()));
+        }
+        if !multi_archive && archive_used + entry.size > u32::MAX as u64 {
+            return Err("VPK data section exceeds 4 GiB, reduce the archive size".to_string());
+        }
+
+        // Start a new archive when this file would overflow the current one
+        if multi_archive && archive_used > 0 && archive_used + entry.size > archive_size {
+            archive_index += 1;
+            archive_used = 0;
+            if archive_index >= EMBEDDED_ARCHIVE_INDEX {
+                return Err("Too many VPK archives, increase the archive size".to_string());
+            }
+        }
+
+        layouts.push(EntryLayout {
+            crc: 0,
+            archive_index: if multi_archive { archive_index } else { EMBEDDED_ARCHIVE_INDEX },
+            offset: archive_used as u32,
+            preload: Vec::new(),
+            shared: false,
+        });
+        archive_used += entry.size;
+    }
+
+    // Tree size depends only on names, so a placeholder tree reserves the exact space
+    let placeholder_tree = build_tree(&entries, &layouts);
 
     // Write VPK file
-    let mut vpk_file = File::create(output_path).map_err(|e| format!("Failed to create VPK: {}", e))?;
+    // Opened for reading too: the v2 whole-file checksum is computed from the written bytes
+    let vpk_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output_path)
+        .map_err(|e| format!("Failed to create VPK: {}", e))?;
+    let mut vpk_file = BufWriter::new(vpk_file);
     
-    // Header (12 bytes for v1)
-    let signature: u32 = 0x55aa1234;
-    let version: u32 = 1;
-    let tree_size: u32 = tree_buffer.len() as u32;
-
-    vpk_file.write_all(&signature.to_le_bytes()).map_err(|e| e.to_string())?;
-    vpk_file.write_all(&version.to_le_bytes()).map_err(|e| e.to_string())?;
-    vpk_file.write_all(&tree_size.to_le_bytes()).map_err(|e| e.to_string())?;
+    // Header (12 bytes for v1, 28 for v2) and tree are rewritten once the data is known
+    let tree_size: u32 = placeholder_tree.len() as u32;
+    let placeholder_header = build_header(options.version, tree_size, 0, 0);
+
+    vpk_file.write_all(&placeholder_header).map_err(|e| e.to_string())?;
+    vpk_file.write_all(&placeholder_tree).map_err(|e| e.to_string())?;
+    drop(placeholder_tree);
+
+    // Remove chunks left over from a previous, larger pack at the same location
+    for stale in vpk_set_files(output_path).into_iter().skip(1) {
+        fs::remove_file(&stale).map_err(|e| format!("Failed to remove {}: {}", stale.display(), e))?;
+    }
+
+    // Stream contents into the data section(s), filling in CRCs as we go
+    let track_md5 = options.version == 2;
+    let mut archive_file: Option<ArchiveWriter> = None;
+    let mut archive_md5s: Vec<ArchiveMd5> = Vec::new();
+
+    // Files already stored in the tree or shared with an earlier entry are skipped
+    let pending: Vec<usize> = (0..layouts.len())
+        .filter(|&index| layouts[index].preload.is_empty() && !layouts[index].shared)
+        .collect();
+    let mut next = 0;
+    while next < pending.len() {
+        // Workers read and hash a batch of small files while this thread writes them in order
+        let batch = read_ahead(&entries, &pending[next..])?;
+        let count = batch.len().max(1);
+        let mut loaded = batch.into_iter();
+
+        for &index in &pending[next..next + count] {
+            let layout = &mut layouts[index];
+            let mut out: &mut dyn Write = if multi_archive {
+                if archive_file.as_ref().map(|archive| archive.index) != Some(layout.archive_index) {
+                    if let Some(previous) = archive_file.take() {
+                        archive_md5s.extend(previous.finish()?);
+                    }
+                    let chunk_path = archive_path(output_path, layout.archive_index);
+                    archive_file = Some(ArchiveWriter::create(&chunk_path, layout.archive_index, track_md5)?);
+                }
+                archive_file.as_mut().unwrap()
+            } else {
+                &mut vpk_file
+            };
+            layout.crc = match loaded.next() {
+                Some((data, crc)) => {
+                    out.write_all(&data).map_err(|e| e.to_string())?;
+                    crc
+                }
+                None => stream_entry(&entries[index], &mut out, &mut buffer)?,
+            };
+        }
+        next += count;
+    }
+    if let Some(last) = archive_file.take() {
+        archive_md5s.extend(last.finish()?);
+    }
+
+    // v2: the archive MD5 section follows the embedded data
+    let file_data_size: u32 = if multi_archive { 0 } else { archive_used as u32 };
+    let mut archive_md5_section: Vec<u8> = Vec::with_capacity(archive_md5s.len() * 28);
+    for record in &archive_md5s {
+        archive_md5_section.extend_from_slice(&record.archive_index.to_le_bytes());
+        archive_md5_section.extend_from_slice(&record.offset.to_le_bytes());
+        archive_md5_section.extend_from_slice(&record.length.to_le_bytes());
+        archive_md5_section.extend_from_slice(&record.md5);
+    }
+    if track_md5 {
+        vpk_file.write_all(&archive_md5_section).map_err(|e| e.to_string())?;
+    }
+
+    // Rewrite the header and tree now that every CRC and section size is known
+    let tree_buffer = build_tree(&entries, &layouts);
+    let header = build_header(options.version, tree_size, file_data_size, archive_md5_section.len() as u32);
+    let mut vpk_file = vpk_file.into_inner().map_err(|e| e.to_string())?;
+    vpk_file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+    vpk_file.write_all(&header).map_err(|e| e.to_string())?;
     vpk_file.write_all(&tree_buffer).map_err(|e| e.to_string())?;
-    vpk_file.write_all(&data_buffer).map_err(|e| e.to_string())?;
 
-    println!("[OK] VPK v1 creado correctamente: {:?}", output_path);
+    // v2: other MD5 section (tree, archive MD5 section, then everything before this checksum)
+    if track_md5 {
+        let tree_md5: [u8; 16] = Md5::digest(&tree_buffer).into();
+        let archive_md5_section_md5: [u8; 16] = Md5::digest(&archive_md5_section).into();
+
+        let written = vpk_file.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+        vpk_file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+        let mut whole_file = Md5::new();
+        let mut reader = (&mut vpk_file).take(written);
+        loop {
+            let read = reader.read(&mut buffer).map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            whole_file.update(&buffer[..read]);
+        }
+        whole_file.update(tree_md5);
+        whole_file.update(archive_md5_section_md5);
+        let whole_file_md5: [u8; 16] = whole_file.finalize().into();
+
+        vpk_file.seek(SeekFrom::Start(written)).map_err(|e| e.to_string())?;
+        vpk_file.write_all(&tree_md5).map_err(|e| e.to_string())?;
+        vpk_file.write_all(&archive_md5_section_md5).map_err(|e| e.to_string())?;
+        vpk_file.write_all(&whole_file_md5).map_err(|e| e.to_string())?;
+    }
+
+    if multi_archive {
+        println!("[OK] VPK v{} creado correctamente: {:?} ({} archivos)", options.version, output_path, archive_index as usize + 1);
+    } else {
+        println!("[OK] VPK v{} creado correctamente: {:?}", options.version, output_path);
+    }
+    if stats.deduplicated_files > 0 {
+        println!(
+            "[OK] {} archivos duplicados compartidos ({} bytes ahorrados)",
+            stats.deduplicated_files, stats.bytes_saved
+        );
+    }
+
+    Ok(stats)
+}
+
+/// Statistics about an incremental update made by `patch_vpk`.
+#[derive(Clone, Debug, Default)]
+pub struct PatchStats {
+    /// Entries whose data was reused from the installed pack
+    pub reused_files: usize,
+    /// Entries whose data was appended to the archives
+    pub written_files: usize,
+    /// Bytes appended to the archives
+    pub bytes_appended: u64,
+    /// Share of archive bytes no longer referenced by the tree after the update
+    pub fragmentation: f64,
+}
+
+/// Updates an installed multi-archive VPK v1 in place so it holds `entries`.
+///
+/// Entries whose path, size and CRC32 match the installed tree keep their data where
+/// it is; everything else is appended to the last archive (or a new one) and the tree
+/// is rewritten. The old tree keeps referencing only untouched ranges until the new
+/// one replaces it, so an interrupted patch leaves the previous pack usable.
+///
+/// The directory file is swapped through an install transaction journaled at
+/// `journal_path`.
+///
+/// Returns `Ok(None)` when the pack can't be patched (missing, v2, data embedded in
+/// the directory file) or when unreferenced bytes would exceed `max_fragmentation`;
+/// the caller should then do a full rebuild.
+pub fn patch_vpk(
+    mut entries: Vec<PackEntry>,
+    pack_path: &Path,
+    options: &PackOptions,
+    max_fragmentation: f64,
+    journal_path: &Path,
+) -> Result<Option<PatchStats>, String> {
+    if options.version != 1 {
+        return Ok(None);
+    }
+    let installed = match VpkArchive::open(pack_path) {
+        Ok(installed) if installed.header.version == 1 => installed,
+        _ => return Ok(None),
+    };
+    if installed.entries.iter().any(|entry| entry.is_embedded() && entry.length > 0) {
+        return Ok(None);
+    }
+    let archive_files = vpk_set_files(pack_path);
+    if archive_files.len() < 2 {
+        return Ok(None);
+    }
+    let last_index = (archive_files.len() - 2) as u16;
+    let archive_lengths: Vec<u64> = archive_files[1..]
+        .iter()
+        .map(|path| fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+        .collect();
+    let existing_bytes: u64 = archive_lengths.iter().sum();
+
+    prepare_entries(&mut entries);
+    let installed_by_path: HashMap<String, &crate::vpk_reader::VpkEntry> = installed
+        .entries
+        .iter()
+        .map(|entry| (entry.full_path(), entry))
+        .collect();
+
+    // Decide what can be reused before touching any file
+    let preload_threshold = options.preload_threshold.min(u16::MAX as u64);
+    let mut layouts: Vec<Option<EntryLayout>> = Vec::with_capacity(entries.len());
+    let mut live_ranges: HashSet<(u16, u32, u32)> = HashSet::new();
+    let mut bytes_to_append: u64 = 0;
+    let mut stats = PatchStats::default();
+
+    for entry in &entries {
+        let reusable = installed_by_path
+            .get(&entry.full_path())
+            .filter(|installed| installed.size() == entry.size && Some(installed.crc) == entry.crc);
+        match reusable {
+            Some(installed) => {
+                if installed.length > 0 {
+                    live_ranges.insert((installed.archive_index, installed.offset, installed.length));
+                }
+                layouts.push(Some(EntryLayout {
+                    crc: installed.crc,
+                    archive_index: installed.archive_index,
+                    offset: installed.offset,
+                    preload: installed.preload.clone(),
+                    shared: false,
+                }));
+                stats.reused_files += 1;
+            }
+            None => {
+                if entry.size > preload_threshold || entry.size == 0 {
+                    bytes_to_append += entry.size;
+                }
+                layouts.push(None);
+            }
+        }
+    }
+
+    let live_bytes: u64 = live_ranges.iter().map(|(_, _, length)| *length as u64).sum::<u64>() + bytes_to_append;
+    let total_bytes = existing_bytes + bytes_to_append;
+    stats.fragmentation = if total_bytes == 0 {
+        0.0
+    } else {
+        1.0 - live_bytes as f64 / total_bytes as f64
+    };
+    if stats.fragmentation > max_fragmentation {
+        println!(
+            "Fragmentación {:.0}% supera el límite, se reconstruirá el VPK completo",
+            stats.fragmentation * 100.0
+        );
+        return Ok(None);
+    }
+
+    // Appended bytes are only referenced once the new tree is in place, so a failed
+    // patch just trims the archives back to their previous sizes
+    if let Err(e) = append_and_swap(&entries, layouts, pack_path, options, last_index, journal_path, &mut stats) {
+        for (index, length) in archive_lengths.iter().enumerate() {
+            if let Ok(file) = OpenOptions::new().write(true).open(archive_path(pack_path, index as u16)) {
+                let _ = file.set_len(*length);
+            }
+        }
+        for index in archive_lengths.len()..EMBEDDED_ARCHIVE_INDEX as usize {
+            if fs::remove_file(archive_path(pack_path, index as u16)).is_err() {
+                break;
+            }
+        }
+        return Err(e);
+    }
+
+    println!(
+        "[OK] VPK actualizado: {} reutilizados, {} escritos ({} bytes)",
+        stats.reused_files, stats.written_files, stats.bytes_appended
+    );
+    Ok(Some(stats))
+}
+
+/// Appends the data of entries without a layout to the archives of `pack_path`,
+/// then swaps in the rewritten directory file.
+fn append_and_swap(
+    entries: &[PackEntry],
+    mut layouts: Vec<Option<EntryLayout>>,
+    pack_path: &Path,
+    options: &PackOptions,
+    mut last_index: u16,
+    journal_path: &Path,
+    stats: &mut PatchStats,
+) -> Result<(), String> {
+    // Append changed data to the archives
+    let archive_size = options.archive_size.clamp(1, u32::MAX as u64);
+    let preload_threshold = options.preload_threshold.min(u16::MAX as u64);
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let mut archive_file: Option<ArchiveWriter> = None;
+    let mut archive_used = fs::metadata(archive_path(pack_path, last_index)).map(|m| m.len()).unwrap_or(0);
+    let mut appended: HashSet<(u16, u32)> = HashSet::new();
+
+    for (entry, layout) in entries.iter().zip(layouts.iter_mut()) {
+        if layout.is_some() {
+            continue;
+        }
+        if entry.size > 0 && entry.size <= preload_threshold {
+            let mut preload = Vec::with_capacity(entry.size as usize);
+            let crc = stream_entry(entry, &mut preload, &mut buffer)?;
+            *layout = Some(EntryLayout {
+                crc,
+                archive_index: EMBEDDED_ARCHIVE_INDEX,
+                offset: 0,
+                preload,
+                shared: false,
+            });
+            stats.written_files += 1;
+            continue;
+        }
+        if entry.size > u32::MAX as u64 {
+            return Err(format!("{} is too large for a VPK entry", entry.full_path()));
+        }
+
+        // Start a new archive when this file would overflow the last one
+        if archive_used > 0 && archive_used + entry.size > archive_size {
+            if let Some(previous) = archive_file.take() {
+                previous.finish()?;
+            }
+            last_index += 1;
+            archive_used = 0;
+            if last_index >= EMBEDDED_ARCHIVE_INDEX {
+                return Err("Too many VPK archives, increase the archive size".to_string());
+            }
+        }
+        if archive_file.is_none() {
+            archive_file = Some(ArchiveWriter::open_append(&archive_path(pack_path, last_index), last_index)?);
+        }
+
+        let crc = stream_entry(entry, archive_file.as_mut().unwrap(), &mut buffer)?;
+        *layout = Some(EntryLayout {
+            crc,
+            archive_index: last_index,
+            offset: archive_used as u32,
+            preload: Vec::new(),
+            shared: false,
+        });
+        appended.insert((last_index, archive_used as u32));
+        archive_used += entry.size;
+        stats.written_files += 1;
+        stats.bytes_appended += entry.size;
+    }
+    if let Some(last) = archive_file.take() {
+        last.finish()?;
+    }
+
+    // Swap in the new tree; the previous directory file is restored if anything fails
+    let layouts: Vec<EntryLayout> = layouts.into_iter().map(|layout| layout.unwrap_or_default()).collect();
+    let tree_buffer = build_tree(entries, &layouts);
+    let mut dir_file = build_header(1, tree_buffer.len() as u32, 0, 0);
+    dir_file.extend_from_slice(&tree_buffer);
+
+    let mut transaction = Transaction::new(journal_path);
+    transaction.stage_bytes(pack_path, &dir_file)?;
+    transaction.apply()?;
+
+    if let Err(e) = verify_patch(pack_path, entries.len(), &appended) {
+        transaction.rollback()?;
+        return Err(format!("Patched VPK failed verification: {}", e));
+    }
+    transaction.commit()
+}
+
+/// Checks a freshly patched pack without re-reading the reused data: the new tree must
+/// parse and hold `entry_count` files, every range must fit in its archive, and the
+/// `appended` ranges and preload data must match their CRC32.
+fn verify_patch(pack_path: &Path, entry_count: usize, appended: &HashSet<(u16, u32)>) -> Result<(), String> {
+    let archive = VpkArchive::open(pack_path).map_err(|e| e.to_string())?;
+    if archive.entries.len() != entry_count {
+        return Err(format!("expected {} files, found {}", entry_count, archive.entries.len()));
+    }
+
+    let mut archive_sizes: HashMap<u16, Option<u64>> = HashMap::new();
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    for entry in &archive.entries {
+        let fresh = appended.contains(&(entry.archive_index, entry.offset));
+        if entry.length > 0 {
+            let available = *archive_sizes.entry(entry.archive_index).or_insert_with(|| {
+                fs::metadata(archive_path(pack_path, entry.archive_index)).map(|m| m.len()).ok()
+            });
+            match available {
+                None => return Err(format!("{}: missing archive {}", entry.full_path(), entry.archive_index)),
+                Some(available) if entry.offset as u64 + entry.length as u64 > available => {
+                    return Err(format!("{} is out of bounds", entry.full_path()));
+                }
+                Some(_) => {}
+            }
+        }
+        if !fresh && entry.length > 0 {
+            continue;
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&entry.preload);
+        if entry.length > 0 {
+            let (data_path, offset) = archive.data_location(entry);
+            read_range(&data_path, offset, entry.length as u64, &mut buffer, |data| hasher.update(data))?;
+        }
+        if hasher.finalize() != entry.crc {
+            return Err(format!("{} has a CRC32 mismatch", entry.full_path()));
+        }
+    }
     Ok(())
 }
+
+/// Identifies a source VPK by size, modification time and the MD5 of its header and tree.
+/// The tree holds every file's CRC32, so content changes alter the result without
+/// reading the file data.
+pub fn source_fingerprint(vpk_path: &Path) -> Result<String, String> {
+    let metadata = fs::metadata(vpk_path).map_err(|e| e.to_string())?;
+    let modified = modified_nanos(&metadata);
+
+    let archive = VpkArchive::open(vpk_path).map_err(|e| e.to_string())?;
+    let mut tree_md5 = Md5::new();
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    read_range(vpk_path, 0, archive.header.data_offset(), &mut buffer, |data| tree_md5.update(data))?;
+
+    Ok(format!("{}:{}:{:x}", metadata.len(), modified, tree_md5.finalize()))
+}
+
+/// Modification time in nanoseconds since the Unix epoch, or 0 when unavailable.
+pub fn modified_nanos(metadata: &fs::Metadata) -> u128 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|since| since.as_nanos())
+        .unwrap_or(0)
+}
+
+/// Reads `len` bytes at `offset` of `path` through `update`, failing if the file is shorter.
+fn read_range(path: &Path, offset: u64, len: u64, buffer: &mut [u8], mut update: impl FnMut(&[u8])) -> Result<(), String> {
+    let mut file = File::open(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+    let mut reader = file.take(len);
+    let mut copied: u64 = 0;
+    loop {
+        let read = reader.read(buffer).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        if read == 0 {
+            break;
+        }
+        update(&buffer[..read]);
+        copied += read as u64;
+    }
+    if copied != len {
+        return Err(format!("{} is truncated", path.display()));
+    }
+    Ok(())
+}
+
+/// Re-reads a VPK and validates its header, tree structure, entry offsets and lengths
+/// against the archive sizes, the v2 MD5 sections and the CRC32 of every file.
+/// Never fails: problems are collected in the returned report.
+pub fn verify_vpk(vpk_path: &Path) -> VerifyReport {
+    let mut report = VerifyReport {
+        path: vpk_path.display().to_string(),
+        ..Default::default()
+    };
+
+    let archive = match VpkArchive::open(vpk_path) {
+        Ok(archive) => archive,
+        Err(e) => {
+            report.errors.push(e.to_string());
+            return report;
+        }
+    };
+    report.version = archive.header.version;
+    report.file_count = archive.entries.len();
+
+    let dir_len = fs::metadata(vpk_path).map(|m| m.len()).unwrap_or(0);
+    let header = &archive.header;
+    let embedded_size = if header.version == 2 {
+        header.file_data_size as u64
+    } else {
+        dir_len.saturating_sub(header.data_offset())
+    };
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+
+    // v2 checksums and signature
+    if header.version == 2 {
+        let sections_start = header.data_offset() + header.file_data_size as u64;
+        let other_md5_start = sections_start + header.archive_md5_size as u64;
+
+        if let Some(other) = &archive.other_md5 {
+            let mut tree_md5 = Md5::new();
+            let mut section_md5 = Md5::new();
+            let mut whole_file_md5 = Md5::new();
+            let checks = [
+                (header.header_size(), header.tree_size as u64, &mut tree_md5, other.tree_md5, "tree"),
+                (sections_start, header.archive_md5_size as u64, &mut section_md5, other.archive_md5_section_md5, "archive MD5 section"),
+                (0, other_md5_start + 32, &mut whole_file_md5, other.whole_file_md5, "whole file"),
+            ];
+            for (offset, len, hasher, expected, what) in checks {
+                match read_range(vpk_path, offset, len, &mut buffer, |data| hasher.update(data)) {
+                    Ok(()) => {
+                        let actual: [u8; 16] = std::mem::take(hasher).finalize().into();
+                        if actual != expected {
+                            report.errors.push(format!("MD5 mismatch for {}", what));
+                        }
+                    }
+                    Err(e) => report.errors.push(e),
+                }
+            }
+        }
+
+        for record in &archive.archive_md5s {
+            let (path, offset) = if record.archive_index == EMBEDDED_ARCHIVE_INDEX as u32 {
+                (vpk_path.to_path_buf(), header.data_offset() + record.offset as u64)
+            } else {
+                (archive_path(vpk_path, record.archive_index as u16), record.offset as u64)
+            };
+            let mut hasher = Md5::new();
+            match read_range(&path, offset, record.length as u64, &mut buffer, |data| hasher.update(data)) {
+                Ok(()) => {
+                    let actual: [u8; 16] = hasher.finalize().into();
+                    if actual != record.md5 {
+                        report.errors.push(format!(
+                            "MD5 mismatch in archive {} at offset {}",
+                            record.archive_index, record.offset
+                        ));
+                    }
+                }
+                Err(e) => report.errors.push(e),
+            }
+        }
+
+        if let Some(signature) = &archive.signature {
+            if signature.public_key.is_empty() || signature.signature.is_empty() {
+                report.errors.push("Empty public key or signature in signature section".to_string());
+            }
+        }
+    }
+
+    // Every entry: bounds against its archive, then CRC32 of the full contents
+    let mut archive_sizes: HashMap<u16, Option<u64>> = HashMap::new();
+    for entry in archive.entries.iter().filter(|entry| !entry.is_embedded()) {
+        archive_sizes.entry(entry.archive_index).or_insert_with(|| {
+            fs::metadata(archive_path(vpk_path, entry.archive_index)).map(|m| m.len()).ok()
+        });
+    }
+
+    let statuses = parallel_map(
+        &archive.entries,
+        || vec![0u8; COPY_BUFFER_SIZE],
+        |buffer, entry| {
+            let available = if entry.length == 0 {
+                Some(u64::MAX)
+            } else if entry.is_embedded() {
+                Some(embedded_size)
+            } else {
+                archive_sizes.get(&entry.archive_index).copied().flatten()
+            };
+
+            match available {
+                None => "missing_archive",
+                Some(available) if entry.offset as u64 + entry.length as u64 > available => "out_of_bounds",
+                Some(_) => {
+                    let mut hasher = crc32fast::Hasher::new();
+                    hasher.update(&entry.preload);
+                    let (data_path, offset) = archive.data_location(entry);
+                    match read_range(&data_path, offset, entry.length as u64, buffer, |data| hasher.update(data)) {
+                        Ok(()) if hasher.finalize() == entry.crc => "ok",
+                        Ok(()) => "crc_mismatch",
+                        Err(_) => "read_error",
+                    }
+                }
+            }
+        },
+    );
+
+    for (entry, status) in archive.entries.iter().zip(statuses) {
+        let path = entry.full_path();
+        if status != "ok" {
+            report.bad_files += 1;
+        }
+        report.files.push(FileCheck {
+            path,
+            size: entry.size(),
+            status: status.to_string(),
+        });
+    }
+
+    report.ok = report.errors.is_empty() && report.bad_files == 0;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+
+    /// Writes every file's contents into one blob and returns entries pointing into it.
+    fn entries_from(dir: &Path, files: &[(&str, &str, &str, &[u8])]) -> Vec<PackEntry> {
+        let blob = dir.join("source.bin");
+        let mut data = Vec::new();
+        let mut entries = Vec::new();
+        for (dir, name, ext, contents) in files {
+            entries.push(PackEntry {
+                ext: ext.to_string(),
+                dir: dir.to_string(),
+                name: name.to_string(),
+                source: EntrySource::Vpk {
+                    archive: blob.clone(),
+                    offset: data.len() as u64,
+                    preload: Vec::new(),
+                },
+                size: contents.len() as u64,
+                crc: None,
+                md5: None,
+            });
+            data.extend_from_slice(contents);
+        }
+        fs::write(&blob, data).unwrap();
+        entries
+    }
+
+    fn read_back(vpk_path: &Path) -> Vec<(String, Vec<u8>)> {
+        let archive = VpkArchive::open(vpk_path).unwrap();
+        let mut files: Vec<_> = archive
+            .entries
+            .iter()
+            .map(|entry| (entry.full_path(), archive.read_entry(entry).unwrap()))
+            .collect();
+        files.sort();
+        files
+    }
+
+    const TREE: &[(&str, &str, &str, &[u8])] = &[
+        ("", "addoninfo", "txt", b"\"AddonInfo\" {}"),
+        ("", "README", "", b"root file without extension"),
+        ("scripts/vscripts", "director_base", "", b"extensionless script"),
+        ("scripts/vscripts", "coop", "nut", b"Msg(\"hi\")"),
+        ("materials/models/survivors", "coach", "vmt", b"\"VertexLitGeneric\" {}"),
+        ("materials/models/survivors", "coach", "vtf", &[7u8; 4096]),
+    ];
+
+    fn expected() -> Vec<(String, Vec<u8>)> {
+        let mut files: Vec<_> = TREE
+            .iter()
+            .map(|(dir, name, ext, contents)| (entry_path(dir, name, ext), contents.to_vec()))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn round_trips_extensionless_and_root_files() {
+        let dir = TestDir::new("roundtrip");
+        let output = dir.0.join("pak01_dir.vpk");
+        pack_vpk(entries_from(&dir.0, TREE), &output, &PackOptions::default()).unwrap();
+
+        assert_eq!(read_back(&output), expected());
+        assert!(verify_vpk(&output).ok);
+
+        let archive = VpkArchive::open(&output).unwrap();
+        let readme = archive.find("README").unwrap();
+        assert_eq!((readme.dir.as_str(), readme.ext.as_str()), (" ", " "));
+    }
+
+    #[test]
+    fn round_trips_multi_archive_v2_with_preload() {
+        let dir = TestDir::new("roundtrip-v2");
+        let output = dir.0.join("pak01_dir.vpk");
+        let options = PackOptions {
+            archive_size: 1024,
+            version: 2,
+            preload_threshold: 64,
+            deduplicate: true,
+            split_archives: false,
+        };
+        pack_vpk(entries_from(&dir.0, TREE), &output, &options).unwrap();
+
+        assert!(archive_path(&output, 0).exists());
+        assert_eq!(read_back(&output), expected());
+        assert!(verify_vpk(&output).ok);
+    }
+
+    /// Packs `files` as a split v1 pack of 1 KiB archives, ready to be patched.
+    fn split_pack(dir: &Path, files: &[(&str, &str, &str, &[u8])]) -> PathBuf {
+        let output = dir.join("pak01_dir.vpk");
+        let options = PackOptions {
+            archive_size: 1024,
+            split_archives: true,
+            ..Default::default()
+        };
+        pack_vpk(entries_from(dir, files), &output, &options).unwrap();
+        output
+    }
+
+    /// Entries for `files` with their CRC32 known, as when they come from a source tree.
+    fn entries_with_crcs(dir: &Path, files: &[(&str, &str, &str, &[u8])]) -> Vec<PackEntry> {
+        let mut entries = entries_from(dir, files);
+        for (entry, (_, _, _, contents)) in entries.iter_mut().zip(files) {
+            entry.crc = Some(crc32fast::hash(contents));
+        }
+        entries
+    }
+
+    fn snapshot(pack_path: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        vpk_set_files(pack_path).into_iter().map(|path| (path.clone(), fs::read(path).unwrap())).collect()
+    }
+
+    #[test]
+    fn patches_changed_new_and_removed_entries() {
+        let dir = TestDir::new("patch");
+        let output = split_pack(&dir.0, TREE);
+        let options = PackOptions {
+            archive_size: 1024,
+            ..Default::default()
+        };
+
+        // README removed, coop.nut changed, mapping.txt new
+        let mut files: Vec<_> = TREE.iter().copied().filter(|(_, name, _, _)| *name != "README").collect();
+        files.retain(|(_, name, _, _)| *name != "coop");
+        files.push(("scripts/vscripts", "coop", "nut", b"Msg(\"bye\")"));
+        files.push(("scripts", "mapping", "txt", b"new file"));
+        let journal = dir.0.join("journal.json");
+        let stats = patch_vpk(entries_with_crcs(&dir.0, &files), &output, &options, 1.0, &journal)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!((stats.reused_files, stats.written_files), (4, 2));
+        assert_eq!(stats.bytes_appended, 10 + 8);
+        let mut expected: Vec<_> = files
+            .iter()
+            .map(|(dir, name, ext, contents)| (entry_path(dir, name, ext), contents.to_vec()))
+            .collect();
+        expected.sort();
+        assert_eq!(read_back(&output), expected);
+        assert!(verify_vpk(&output).ok);
+        assert!(!journal.exists());
+    }
+
+    #[test]
+    fn refuses_to_patch_past_the_fragmentation_limit() {
+        let dir = TestDir::new("patch-fragmented");
+        let output = split_pack(&dir.0, TREE);
+        let before = snapshot(&output);
+
+        // Replacing the large texture leaves most archive bytes unreferenced
+        let mut files: Vec<_> = TREE.iter().copied().filter(|(_, _, ext, _)| *ext != "vtf").collect();
+        files.push(("materials/models/survivors", "coach", "vtf", &[8u8; 4096]));
+        let patched = patch_vpk(
+            entries_with_crcs(&dir.0, &files),
+            &output,
+            &PackOptions::default(),
+            0.25,
+            &dir.0.join("journal.json"),
+        )
+        .unwrap();
+
+        assert!(patched.is_none());
+        assert_eq!(snapshot(&output), before);
+    }
+
+    #[test]
+    fn failed_patch_leaves_the_pack_untouched() {
+        let dir = TestDir::new("patch-failed");
+        let output = split_pack(&dir.0, TREE);
+        let archives = vpk_set_files(&output);
+        assert!(archives.len() > 2);
+
+        // A damaged first archive makes the entries reused from it fail the check
+        let first = &archives[1];
+        let length = fs::metadata(first).unwrap().len();
+        OpenOptions::new().write(true).open(first).unwrap().set_len(length / 2).unwrap();
+        let before = snapshot(&output);
+
+        let mut files = TREE.to_vec();
+        files.push(("scripts", "mapping", "txt", b"new file"));
+        let result = patch_vpk(
+            entries_with_crcs(&dir.0, &files),
+            &output,
+            &PackOptions::default(),
+            1.0,
+            &dir.0.join("journal.json"),
+        );
+
+        assert!(result.unwrap_err().contains("failed verification"));
+        assert_eq!(snapshot(&output), before);
+    }
+
+    #[test]
+    fn deduplicates_identical_files() {
+        let dir = TestDir::new("dedup");
+        let output = dir.0.join("pak01_dir.vpk");
+        let files: &[(&str, &str, &str, &[u8])] = &[
+            ("materials/a", "skin", "vtf", &[1u8; 1000]),
+            ("materials/b", "skin", "vtf", &[1u8; 1000]),
+            ("materials/c", "skin", "vtf", &[2u8; 1000]),
+            ("materials/d", "skin", "vtf", &[1u8; 1000]),
+            ("materials/e", "small", "vmt", b"unique"),
+        ];
+        let options = PackOptions {
+            deduplicate: true,
+            ..Default::default()
+        };
+        let stats = pack_vpk(entries_from(&dir.0, files), &output, &options).unwrap();
+
+        assert_eq!(stats.deduplicated_files, 2);
+        assert_eq!(stats.bytes_saved, 2000);
+        // Two copies are stored once each: the data section holds 1000 + 1000 + 6 bytes
+        let archive = VpkArchive::open(&output).unwrap();
+        let data_size = fs::metadata(&output).unwrap().len() - archive.header.data_offset();
+        assert_eq!(data_size, 2006);
+        assert_eq!(read_back(&output).len(), 5);
+        assert!(verify_vpk(&output).ok);
+    }
+
+    #[test]
+    fn keeps_files_with_colliding_crcs_apart() {
+        let dir = TestDir::new("dedup-crc");
+        let output = dir.0.join("pak01_dir.vpk");
+        // "plumless" and "buckeroo" share their size and CRC32 (0x4ddb0c25)
+        let files: &[(&str, &str, &str, &[u8])] = &[("a", "first", "txt", b"plumless"), ("a", "second", "txt", b"buckeroo")];
+        let mut entries = entries_from(&dir.0, files);
+        for entry in &mut entries {
+            entry.crc = Some(0x4ddb_0c25);
+        }
+        let options = PackOptions {
+            deduplicate: true,
+            ..Default::default()
+        };
+        let stats = pack_vpk(entries, &output, &options).unwrap();
+
+        assert_eq!(stats.deduplicated_files, 0);
+        assert_eq!(
+            read_back(&output),
+            vec![("a/first.txt".to_string(), b"plumless".to_vec()), ("a/second.txt".to_string(), b"buckeroo".to_vec())]
+        );
+    }
+}
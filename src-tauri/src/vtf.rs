@@ -0,0 +1,287 @@
+//! Minimal VTF (Valve Texture Format) support for shrinking textures
+//!
+//! Only what is needed to drop the largest mip levels of a texture: the header,
+//! the resource table of 7.3+ files and the size of each image format.
+
+/// "VTF\0"
+const VTF_SIGNATURE: &[u8; 4] = b"VTF\0";
+
+/// Texture is a cubemap; faces are skipped rather than resized
+const FLAG_ENVMAP: u32 = 0x4000;
+/// Texture opts out of picmip (UI, fonts, ...); it must keep its resolution
+const FLAG_NOLOD: u32 = 0x200;
+
+/// Resource tag of the high-resolution image data (7.3+)
+const HIGH_RES_TAG: [u8; 3] = [0x30, 0, 0];
+/// Resource tag of the CRC32 computed over the original image data (7.3+)
+const CRC_TAG: [u8; 3] = *b"CRC";
+/// Resource flag meaning the entry stores its value inline, not an offset
+const RESOURCE_NO_DATA: u8 = 0x02;
+
+// Header field offsets (the header is a packed struct)
+const VERSION_MINOR: usize = 8;
+const HEADER_SIZE: usize = 12;
+const WIDTH: usize = 16;
+const HEIGHT: usize = 18;
+const FLAGS: usize = 20;
+const FRAMES: usize = 24;
+const HIGH_RES_FORMAT: usize = 52;
+const MIPMAP_COUNT: usize = 56;
+const LOW_RES_FORMAT: usize = 57;
+const LOW_RES_WIDTH: usize = 61;
+const LOW_RES_HEIGHT: usize = 62;
+const DEPTH: usize = 63;
+const RESOURCE_COUNT: usize = 68;
+const RESOURCES: usize = 80;
+
+/// Returns the size in bytes of a `width` x `height` image, or `None` for formats
+/// this module doesn't know.
+fn image_size(format: u32, width: u32, height: u32) -> Option<u64> {
+    let (width, height) = (width.max(1) as u64, height.max(1) as u64);
+    let block_bytes = match format {
+        13 | 20 => Some(8),  // DXT1, DXT1_ONEBITALPHA
+        14 | 15 => Some(16), // DXT3, DXT5
+        _ => None,
+    };
+    if let Some(block_bytes) = block_bytes {
+        return Some(width.div_ceil(4) * height.div_ceil(4) * block_bytes);
+    }
+    let bytes_per_pixel = match format {
+        5 | 7 | 8 => 1,                        // I8, P8, A8
+        4 | 6 | 17 | 18 | 19 | 21 | 22 => 2,   // RGB565, IA88, BGR565, BGRX5551, BGRA4444, BGRA5551, UV88
+        2 | 3 | 9 | 10 => 3,                   // RGB888, BGR888 and their bluescreen variants
+        0 | 1 | 11 | 12 | 16 | 23 | 26 => 4,   // RGBA8888, ABGR8888, ARGB8888, BGRA8888, BGRX8888, UVWQ8888, UVLX8888
+        24 | 25 => 8,                          // RGBA16161616F, RGBA16161616
+        _ => return None,
+    };
+    Some(width * height * bytes_per_pixel)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// Rewrites a VTF without its `levels` largest mip levels.
+///
+/// Fewer levels are dropped if that would take the largest side below `min_size`.
+/// Returns `None` when the texture is left as is: nothing to drop, an unknown format,
+/// a cubemap or volume texture, a texture flagged as no-LOD, or a malformed file.
+pub fn drop_mip_levels(data: &[u8], levels: u32, min_size: u32) -> Option<Vec<u8>> {
+    if data.get(..4)? != VTF_SIGNATURE || read_u32(data, 4)? != 7 {
+        return None;
+    }
+    let minor = read_u32(data, VERSION_MINOR)?;
+    let header_size = read_u32(data, HEADER_SIZE)? as usize;
+    let width = read_u16(data, WIDTH)? as u32;
+    let height = read_u16(data, HEIGHT)? as u32;
+    let flags = read_u32(data, FLAGS)?;
+    let frames = read_u16(data, FRAMES)?.max(1) as u64;
+    let format = read_u32(data, HIGH_RES_FORMAT)?;
+    let mip_count = *data.get(MIPMAP_COUNT)? as u32;
+    let depth = if minor >= 2 { read_u16(data, DEPTH)?.max(1) } else { 1 };
+
+    if flags & (FLAG_ENVMAP | FLAG_NOLOD) != 0 || depth != 1 || minor > 5 {
+        return None;
+    }
+
+    // Mips are dropped from the top, never below min_size or the last level
+    let mut drop = 0;
+    while drop < levels && drop + 1 < mip_count && (width.max(height) >> (drop + 1)) >= min_size.max(1) {
+        drop += 1;
+    }
+    if drop == 0 {
+        return None;
+    }
+
+    // Data is stored from the smallest mip to the largest, each holding every frame
+    let mip_bytes = |level: u32| image_size(format, width >> level, height >> level).map(|size| size * frames);
+    let mut kept_size: u64 = 0;
+    for level in drop..mip_count {
+        kept_size += mip_bytes(level)?;
+    }
+    let mut dropped_size: u64 = 0;
+    for level in 0..drop {
+        dropped_size += mip_bytes(level)?;
+    }
+
+    // Locate the high-res data: a resource from 7.3 on, right after the thumbnail before.
+    // The CRC resource describes the full-size image, so it is dropped with the mips.
+    let mut resource_count = 0;
+    let mut crc_entry = None;
+    let (high_res_offset, resource_offsets) = if minor >= 3 {
+        resource_count = read_u32(data, RESOURCE_COUNT)? as usize;
+        if RESOURCES + resource_count * 8 > header_size {
+            return None;
+        }
+        let mut high_res = None;
+        let mut offsets = Vec::new();
+        for index in 0..resource_count {
+            let entry = RESOURCES + index * 8;
+            let tag = data.get(entry..entry + 3)?;
+            let resource_flags = *data.get(entry + 3)?;
+            if tag == HIGH_RES_TAG {
+                high_res = Some(read_u32(data, entry + 4)? as u64);
+            }
+            if tag == CRC_TAG {
+                crc_entry = Some(entry);
+            } else if resource_flags & RESOURCE_NO_DATA == 0 {
+                offsets.push(entry + 4);
+            }
+        }
+        (high_res?, offsets)
+    } else {
+        let low_res_format = read_u32(data, LOW_RES_FORMAT)?;
+        let low_res_width = *data.get(LOW_RES_WIDTH)? as u32;
+        let low_res_height = *data.get(LOW_RES_HEIGHT)? as u32;
+        let low_res_size = if low_res_format == u32::MAX || low_res_width == 0 || low_res_height == 0 {
+            0
+        } else {
+            image_size(low_res_format, low_res_width, low_res_height)?
+        };
+        (header_size as u64 + low_res_size, Vec::new())
+    };
+
+    // Image data pointing into the header would cut the fields patched below
+    if header_size <= MIPMAP_COUNT || high_res_offset < header_size as u64 {
+        return None;
+    }
+    let drop_start = high_res_offset + kept_size;
+    let drop_end = drop_start + dropped_size;
+    if drop_end > data.len() as u64 {
+        return None;
+    }
+    let (drop_start, drop_end) = (drop_start as usize, drop_end as usize);
+
+    // Input ranges left out of the output, in file order; offsets past a range move back by its length
+    let mut removed = Vec::new();
+    if let Some(entry) = crc_entry {
+        removed.push((entry, entry + 8));
+    }
+    removed.push((drop_start, drop_end));
+    let moved = |offset: usize| -> usize {
+        offset - removed.iter().filter(|(_, end)| *end <= offset).map(|(start, end)| end - start).sum::<usize>()
+    };
+
+    let mut output = Vec::with_capacity(data.len());
+    let mut copied = 0;
+    for (start, end) in &removed {
+        output.extend_from_slice(&data[copied..*start]);
+        copied = *end;
+    }
+    output.extend_from_slice(&data[copied..]);
+
+    output[WIDTH..WIDTH + 2].copy_from_slice(&((width >> drop).max(1) as u16).to_le_bytes());
+    output[HEIGHT..HEIGHT + 2].copy_from_slice(&((height >> drop).max(1) as u16).to_le_bytes());
+    output[MIPMAP_COUNT] = (mip_count - drop) as u8;
+    if crc_entry.is_some() {
+        output[HEADER_SIZE..HEADER_SIZE + 4].copy_from_slice(&((header_size - 8) as u32).to_le_bytes());
+        output[RESOURCE_COUNT..RESOURCE_COUNT + 4].copy_from_slice(&((resource_count - 1) as u32).to_le_bytes());
+    }
+
+    for field in resource_offsets {
+        let offset = read_u32(data, field)? as usize;
+        let field = moved(field);
+        output[field..field + 4].copy_from_slice(&(moved(offset) as u32).to_le_bytes());
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A DXT1 `size` x `size` texture with `mips` levels and no thumbnail. Each level is
+    /// filled with its own index; `resources` (tag, flags, value) are written from 7.3 on.
+    fn dxt1(minor: u32, header_size: u32, size: u16, mips: u8, resources: &[([u8; 3], u8, u32)]) -> Vec<u8> {
+        let mut data = vec![0u8; header_size as usize];
+        data[..4].copy_from_slice(VTF_SIGNATURE);
+        data[4..8].copy_from_slice(&7u32.to_le_bytes());
+        data[VERSION_MINOR..VERSION_MINOR + 4].copy_from_slice(&minor.to_le_bytes());
+        data[HEADER_SIZE..HEADER_SIZE + 4].copy_from_slice(&header_size.to_le_bytes());
+        data[WIDTH..WIDTH + 2].copy_from_slice(&size.to_le_bytes());
+        data[HEIGHT..HEIGHT + 2].copy_from_slice(&size.to_le_bytes());
+        data[FRAMES..FRAMES + 2].copy_from_slice(&1u16.to_le_bytes());
+        data[HIGH_RES_FORMAT..HIGH_RES_FORMAT + 4].copy_from_slice(&13u32.to_le_bytes());
+        data[MIPMAP_COUNT] = mips;
+        data[LOW_RES_FORMAT..LOW_RES_FORMAT + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        if minor >= 2 {
+            data[DEPTH..DEPTH + 2].copy_from_slice(&1u16.to_le_bytes());
+        }
+        if minor >= 3 {
+            data[RESOURCE_COUNT..RESOURCE_COUNT + 4].copy_from_slice(&(resources.len() as u32).to_le_bytes());
+            for (index, (tag, flags, value)) in resources.iter().enumerate() {
+                let entry = RESOURCES + index * 8;
+                data[entry..entry + 3].copy_from_slice(tag);
+                data[entry + 3] = *flags;
+                data[entry + 4..entry + 8].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        for level in (0..mips).rev() {
+            let level_size = image_size(13, (size >> level) as u32, (size >> level) as u32).unwrap();
+            data.extend(vec![level; level_size as usize]);
+        }
+        data
+    }
+
+    /// A 7.3 DXT1 8x8 texture with 4 mips whose high-res resource points at `high_res_offset`.
+    fn dxt1_7_3(high_res_offset: u32) -> Vec<u8> {
+        dxt1(3, 88, 8, 4, &[(HIGH_RES_TAG, 0, high_res_offset)])
+    }
+
+    #[test]
+    fn drops_the_largest_mip() {
+        let output = drop_mip_levels(&dxt1_7_3(88), 1, 1).unwrap();
+        // 8x8 DXT1 is 32 bytes; the 4x4, 2x2 and 1x1 levels (8 bytes each) remain
+        assert_eq!(output.len(), 88 + 24);
+        assert_eq!(read_u16(&output, WIDTH), Some(4));
+        assert_eq!(output[MIPMAP_COUNT], 3);
+    }
+
+    #[test]
+    fn ignores_image_data_inside_the_header() {
+        let mut data = dxt1_7_3(0);
+        data.truncate(88);
+        assert!(drop_mip_levels(&data, 1, 1).is_none());
+        assert!(drop_mip_levels(&dxt1_7_3(40), 1, 1).is_none());
+    }
+
+    #[test]
+    fn drops_mips_of_headers_without_resources() {
+        // 7.2: 80-byte header, data right after it
+        let output = drop_mip_levels(&dxt1(2, 80, 8, 4, &[]), 1, 1).unwrap();
+        assert_eq!(output[..80], dxt1(2, 80, 4, 3, &[])[..80]);
+        assert_eq!(output[80..], [vec![3u8; 8], vec![2u8; 8], vec![1u8; 8]].concat());
+
+        // 7.1: 64-byte header, the dropped range starts before where 7.3 resources would end
+        let output = drop_mip_levels(&dxt1(1, 64, 2, 2, &[]), 1, 1).unwrap();
+        assert_eq!(output.len(), 64 + 8);
+        assert_eq!(output[64..], [1u8; 8]);
+    }
+
+    #[test]
+    fn drops_the_crc_resource() {
+        let mut data = dxt1(
+            4,
+            104,
+            8,
+            4,
+            &[(CRC_TAG, RESOURCE_NO_DATA, 0xdead_beef), (HIGH_RES_TAG, 0, 104), (*b"KVD", 0, 160)],
+        );
+        data.extend_from_slice(b"kv\0\0");
+
+        let output = drop_mip_levels(&data, 1, 1).unwrap();
+        assert_eq!(output.len(), data.len() - 8 - 32);
+        assert_eq!(read_u32(&output, HEADER_SIZE), Some(96));
+        assert_eq!(read_u32(&output, RESOURCE_COUNT), Some(2));
+        assert_eq!(output[RESOURCES..RESOURCES + 3], HIGH_RES_TAG);
+        assert_eq!(read_u32(&output, RESOURCES + 4), Some(96));
+        assert_eq!(output[RESOURCES + 8..RESOURCES + 11], *b"KVD");
+        assert_eq!(read_u32(&output, RESOURCES + 12), Some(120));
+        assert_eq!(output[96..120], [vec![3u8; 8], vec![2u8; 8], vec![1u8; 8]].concat());
+        assert_eq!(output[120..], *b"kv\0\0");
+    }
+}